@@ -0,0 +1,405 @@
+use crate::collect::{get_datetime_from_archived, read_headers};
+use crate::compress::{self, Codec, Encoder};
+use crate::mbox::{self, MboxFormat, MboxReader};
+use crate::utils::get_file_name;
+use crate::verify::{hash_bytes, to_hex, HashResult, StreamHasher};
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset};
+use clap::ValueEnum;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, Read, Write};
+use std::mem;
+use std::path::PathBuf;
+use tar::{self, Archive as TarArchive, Builder as TarBuilder};
+
+/// How emails are stored in archives before compression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ArchiveFormat {
+    /// A tar archive with a file per email.
+    Tar,
+    /// An mbox file in mboxrd format, which can be opened by mail clients.
+    Mbox,
+}
+
+impl ArchiveFormat {
+    const ALL: [ArchiveFormat; 2] = [ArchiveFormat::Tar, ArchiveFormat::Mbox];
+
+    fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => ".tar",
+            ArchiveFormat::Mbox => ".mbox",
+        }
+    }
+}
+
+/// Kind of archive files, i.e. the format and the compression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArchiveKind {
+    pub format: ArchiveFormat,
+    pub codec: Codec,
+}
+
+impl ArchiveKind {
+    fn all() -> impl Iterator<Item = ArchiveKind> {
+        ArchiveFormat::ALL.iter().flat_map(|&format| {
+            Codec::ALL
+                .iter()
+                .map(move |&codec| ArchiveKind { format, codec })
+        })
+    }
+
+    /// File name suffix of archives of this kind, e.g. `.tar.xz`.
+    pub fn suffix(self) -> String {
+        format!("{}{}", self.format.extension(), self.codec.extension())
+    }
+
+    /// Suffixes of archives of all kinds, with the one of this kind first.
+    pub fn suffixes(self) -> impl Iterator<Item = String> {
+        let others = ArchiveKind::all().filter(move |&kind| kind != self);
+        std::iter::once(self).chain(others).map(ArchiveKind::suffix)
+    }
+
+    /// Returns the archive name stripped from the given file name if it is
+    /// an archive of any kind.
+    pub fn strip_suffix(file_name: &str) -> Option<&str> {
+        ArchiveKind::split_suffix(file_name).map(|(name, _)| name)
+    }
+
+    /// Returns the archive name and the kind of the given file name if it
+    /// is an archive of any kind.
+    pub fn split_suffix(file_name: &str) -> Option<(&str, ArchiveKind)> {
+        ArchiveKind::all().find_map(|kind| {
+            let name = file_name.strip_suffix(&kind.suffix())?;
+            Some((name, kind))
+        })
+    }
+}
+
+/// Bytes kept from the start of the content of an entry, which is enough
+/// for the headers needed by the index.
+const HEAD_LEN: usize = 64 * 1024;
+
+/// Where the content of an entry is read from.
+enum Source<'a> {
+    Bytes(Cursor<Vec<u8>>),
+    Stream(Box<dyn Read + 'a>),
+}
+
+impl Read for Source<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::Bytes(cursor) => cursor.read(buf),
+            Source::Stream(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Reader of the content of an entry, which hashes the content as it is
+/// read, and keeps its start where the headers are.
+struct ContentReader<'a> {
+    hasher: StreamHasher<Source<'a>>,
+    size: u64,
+    head: Vec<u8>,
+}
+
+impl Read for ContentReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.hasher.read(buf)?;
+        self.size += size as u64;
+        let kept = size.min(HEAD_LEN.saturating_sub(self.head.len()));
+        self.head.extend_from_slice(&buf[..kept]);
+        Ok(size)
+    }
+}
+
+/// What is known about the content of an entry after reading it through.
+pub struct ContentDigest {
+    pub hash: HashResult,
+    pub size: u64,
+    /// The start of the content, which holds the headers unless they are
+    /// unusually long.
+    pub head: Vec<u8>,
+}
+
+/// An email in an archive. Its content is read as a stream, except for
+/// emails in mbox archives, which are kept in memory for handling their
+/// `From ` lines.
+pub struct Entry<'a> {
+    pub path: PathBuf,
+    /// Size of the content, which is known before reading it.
+    pub size: u64,
+    /// The header of the entry in a tar archive.
+    pub header: Option<tar::Header>,
+    /// The `From ` line of the entry in an mbox archive, without `From `.
+    pub from_line: Option<Vec<u8>>,
+    content: ContentReader<'a>,
+}
+
+/// Returns the name of an email in mbox archives, where emails have no
+/// names, which is made from the hash of its content.
+fn get_mbox_entry_name(content: &[u8]) -> OsString {
+    OsString::from(format!("mbox-{}", &to_hex(&hash_bytes(content))[..40]))
+}
+
+impl<'a> Entry<'a> {
+    fn with_source(path: PathBuf, size: u64, source: Source<'a>) -> Self {
+        Entry {
+            path,
+            size,
+            header: None,
+            from_line: None,
+            content: ContentReader {
+                hasher: StreamHasher::new(source),
+                size: 0,
+                head: Vec::new(),
+            },
+        }
+    }
+
+    pub fn new(path: PathBuf, content: Vec<u8>) -> Self {
+        let size = content.len() as u64;
+        Entry::with_source(path, size, Source::Bytes(Cursor::new(content)))
+    }
+
+    /// Makes an entry whose content of the given size is read from the
+    /// reader as it is consumed.
+    pub fn from_reader(path: PathBuf, size: u64, reader: impl Read + 'a) -> Self {
+        Entry::with_source(path, size, Source::Stream(Box::new(reader)))
+    }
+
+    /// Converts the entry into one of archives in the given format. In mbox
+    /// archives, the `From ` line is kept apart from the content, which is
+    /// normalized and gives the name of the entry, so the content is read
+    /// into memory.
+    pub fn into_format(mut self, format: ArchiveFormat) -> io::Result<Self> {
+        if format == ArchiveFormat::Tar {
+            self.from_line = None;
+            return Ok(self);
+        }
+        let content = match self.content.hasher.into_inner() {
+            Source::Bytes(cursor) if cursor.position() == 0 => cursor.into_inner(),
+            mut source => {
+                let mut content = Vec::with_capacity(self.size as usize);
+                source.read_to_end(&mut content)?;
+                content
+            }
+        };
+        let (from_line, content) = mbox::split_from_line(content);
+        let path = PathBuf::from(get_mbox_entry_name(&content));
+        let mut entry = Entry::new(path, content);
+        entry.header = self.header;
+        entry.from_line = from_line.or(self.from_line);
+        Ok(entry)
+    }
+
+    /// Reads the rest of the content into memory.
+    pub fn read_content(&mut self) -> io::Result<Vec<u8>> {
+        let mut content = Vec::with_capacity(self.size.saturating_sub(self.content.size) as usize);
+        self.content.read_to_end(&mut content)?;
+        Ok(content)
+    }
+
+    /// Reads the rest of the content through, and returns the digest of the
+    /// whole content.
+    pub fn digest(&mut self) -> io::Result<ContentDigest> {
+        io::copy(&mut self.content, &mut io::sink())?;
+        Ok(ContentDigest {
+            hash: self.content.hasher.get_result(),
+            size: self.content.size,
+            head: mem::take(&mut self.content.head),
+        })
+    }
+}
+
+impl Read for Entry<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.content.read(buf)
+    }
+}
+
+/// Reads every email in the archive, which is either a tar archive or an
+/// mbox file told from its content, and passes it to the given function.
+/// Content of the entry which isn't read by the function is skipped.
+pub fn read_entries(src: File, mut f: impl FnMut(Entry<'_>) -> Result<()>) -> Result<()> {
+    let mut reader = BufReader::new(compress::open_archive(src)?);
+    if reader.fill_buf()?.starts_with(b"From ") {
+        for message in MboxReader::new(reader, MboxFormat::Mboxrd) {
+            f(Entry::new(PathBuf::new(), message?).into_format(ArchiveFormat::Mbox)?)?;
+        }
+        return Ok(());
+    }
+
+    let mut tar_archive = TarArchive::new(reader);
+    // Each appended stream holds a complete tar archive, so the
+    // end-of-archive blocks between them are skipped.
+    tar_archive.set_ignore_zeros(true);
+    for entry in tar_archive.entries()? {
+        let entry = entry?;
+        // We have to clone the header, otherwise we cannot feed the entry
+        // to builder.append(). See alexcrichton/tar-rs#122.
+        let header = entry.header().clone();
+        let path = header.path()?.into_owned();
+        let file_name = get_file_name(&path).to_os_string();
+        let mut entry = Entry::from_reader(path, header.size()?, entry);
+        entry.header = Some(header);
+        f(entry).with_context(|| format!("failed to read file {:?}", file_name))?;
+    }
+    Ok(())
+}
+
+/// Writer of archives in either format.
+pub enum ArchiveWriter<W: Write> {
+    Tar(TarBuilder<Encoder<W>>),
+    Mbox(Encoder<W>),
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(format: ArchiveFormat, encoder: Encoder<W>) -> Self {
+        match format {
+            ArchiveFormat::Tar => {
+                let mut tar_builder = TarBuilder::new(encoder);
+                tar_builder.mode(tar::HeaderMode::Deterministic);
+                ArchiveWriter::Tar(tar_builder)
+            }
+            ArchiveFormat::Mbox => ArchiveWriter::Mbox(encoder),
+        }
+    }
+
+    /// Appends the entry, which must be in the format of the archive. The tar
+    /// header is made from the content if the entry has none. The `From `
+    /// line of mbox is kept if the entry has one, otherwise it is made from
+    /// the headers and the given date, or the date found in the email if it
+    /// isn't given. The content is read through, so the entry can tell its
+    /// digest afterwards.
+    pub fn append(
+        &mut self,
+        entry: &mut Entry,
+        date: Option<&DateTime<FixedOffset>>,
+    ) -> io::Result<()> {
+        match self {
+            ArchiveWriter::Tar(tar_builder) => {
+                let mut header = match &entry.header {
+                    Some(header) => header.clone(),
+                    None => {
+                        let mut header = tar::Header::new_gnu();
+                        header.set_mode(0o644);
+                        header
+                    }
+                };
+                header.set_size(entry.size);
+                let content = (&mut entry.content).take(entry.size);
+                tar_builder.append_data(&mut header, &entry.path, content)?;
+                if entry.content.size != entry.size {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the size changed while reading",
+                    ));
+                }
+                Ok(())
+            }
+            ArchiveWriter::Mbox(encoder) => {
+                let content = entry.read_content()?;
+                if let Some(from_line) = &entry.from_line {
+                    return mbox::write_message(encoder, from_line, &content);
+                }
+                let headers = read_headers(&content[..])?;
+                let date = date
+                    .copied()
+                    .or_else(|| get_datetime_from_archived(&entry.path, &headers));
+                let from_line = mbox::get_from_line(&headers, date.as_ref());
+                mbox::write_message(encoder, &from_line, &content)
+            }
+        }
+    }
+
+    /// Finishes the archive and returns the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            ArchiveWriter::Tar(tar_builder) => tar_builder.into_inner()?.finish(),
+            ArchiveWriter::Mbox(encoder) => encoder.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_suffix() {
+        assert_eq!(ArchiveKind::strip_suffix("2017-06.tar.xz"), Some("2017-06"));
+        assert_eq!(
+            ArchiveKind::strip_suffix("2017-06.tar.zst"),
+            Some("2017-06")
+        );
+        assert_eq!(ArchiveKind::strip_suffix("2017-06.tar"), Some("2017-06"));
+        assert_eq!(
+            ArchiveKind::strip_suffix("2017-06.mbox.xz"),
+            Some("2017-06")
+        );
+        assert_eq!(ArchiveKind::strip_suffix("2017-06.tar.xz.bak"), None);
+        assert_eq!(ArchiveKind::strip_suffix("2017-06.tar.gz.tmp"), None);
+        assert_eq!(
+            ArchiveKind::split_suffix("2017/06.tar.bz2"),
+            Some((
+                "2017/06",
+                ArchiveKind {
+                    format: ArchiveFormat::Tar,
+                    codec: Codec::Bzip2
+                }
+            ))
+        );
+        assert_eq!(
+            ArchiveKind::split_suffix("2017-06.mbox"),
+            Some((
+                "2017-06",
+                ArchiveKind {
+                    format: ArchiveFormat::Mbox,
+                    codec: Codec::None
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn test_mbox_round_trip() {
+        let emails: [&[u8]; 3] = [
+            b"From: a@example.com\nSubject: a\n\nFrom here\n>From there\n",
+            b"Return-Path: <b@example.com>\r\nSubject: b\r\n\r\nbody\r\n\r\n",
+            b"From c Mon Jan  2 03:04:05 2006\r\nSubject: c\n\nno newline",
+        ];
+        let mut file = tempfile::tempfile().unwrap();
+        let mut writer = ArchiveWriter::new(
+            ArchiveFormat::Mbox,
+            Encoder::new(Codec::Xz, 6, &file).unwrap(),
+        );
+        let mut expected = vec![];
+        for email in emails.iter() {
+            let mut entry = Entry::new(PathBuf::from("x"), email.to_vec())
+                .into_format(ArchiveFormat::Mbox)
+                .unwrap();
+            writer.append(&mut entry, None).unwrap();
+            let (_, content) = mbox::split_from_line(email.to_vec());
+            expected.push((entry.path, content));
+        }
+        writer.finish().unwrap();
+        io::Seek::rewind(&mut file).unwrap();
+        let mut entries = vec![];
+        let mut from_lines = vec![];
+        read_entries(file, |mut entry| {
+            let content = entry.read_content()?;
+            from_lines.push(entry.from_line.unwrap());
+            entries.push((entry.path, content));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(entries, expected);
+        assert!(!expected[2].1.starts_with(b"From "));
+        // The `From ` line is made from the headers, or kept if the email
+        // has one.
+        assert!(from_lines[0].starts_with(b"a@example.com "));
+        assert!(from_lines[1].starts_with(b"b@example.com "));
+        assert_eq!(from_lines[2], b"c Mon Jan  2 03:04:05 2006");
+    }
+}
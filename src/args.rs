@@ -1,24 +1,364 @@
-use clap::Parser;
+use crate::archive::{ArchiveFormat, ArchiveKind};
+use crate::classify::{BucketTimeZone, Granularity};
+use crate::collect::{AgeBasis, DateSource};
+use crate::compress::Codec;
+use crate::mbox::MboxFormat;
+use crate::template::NameTemplate;
+use chrono::{Duration, NaiveDate};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand};
 use std::path::PathBuf;
 
 #[derive(Debug, Parser)]
 #[clap(name = "maildir-pack")]
 #[clap(author, version, about)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Args {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
     /// Path to the maildir.
+    #[clap(id = "maildir", value_name = "MAILDIR", required = true)]
+    maildir_arg: Option<PathBuf>,
+    /// Path to the maildir, resolved from the argument or the subcommand.
+    #[clap(skip)]
     pub maildir: PathBuf,
     /// The directory we put packed archives in, which is maildir/packed.
     #[clap(skip)]
     pub packed_dir: PathBuf,
-    /// Suppress any progress output if set.
+    /// Also pack emails in maildir/cur, not only those in maildir/new.
+    #[clap(long)]
+    pub include_cur: bool,
+    /// Also pack messages in the mbox file, which is left untouched. It can
+    /// be given multiple times. Messages are named after the hash of their
+    /// content, so packing the same mbox again adds nothing.
+    #[clap(long, value_name = "FILE")]
+    pub mbox: Vec<PathBuf>,
+    /// Variant of the mbox format of --mbox.
+    #[clap(long, value_enum, default_value = "mboxrd")]
+    pub mbox_format: MboxFormat,
+    /// Also pack messages in the MH folder, which is left untouched. It can
+    /// be given multiple times, and subfolders are not included. Messages
+    /// are named after the hash of their content as with --mbox.
+    #[clap(long, value_name = "DIR")]
+    pub mh: Vec<PathBuf>,
+    /// Only pack messages of MH folders in the given sequence, e.g. `cur`.
+    /// It can be given multiple times to pack messages in any of them.
+    #[clap(long, value_name = "NAME", requires = "mh")]
+    pub mh_sequence: Vec<String>,
+    /// Don't pack messages of MH folders in the given sequence, e.g.
+    /// `unseen`. It can be given multiple times.
+    #[clap(long, value_name = "NAME", requires = "mh")]
+    pub mh_exclude_sequence: Vec<String>,
+    /// Also pack every Maildir++ subfolder, each into its own subdirectory
+    /// of maildir/packed.
+    #[clap(short, long)]
+    pub recursive: bool,
+    /// Where to get the date of an email from. Sources are tried in the
+    /// given order until one of them supplies a valid date.
+    #[clap(long, value_enum, value_delimiter = ',', default_value = "date")]
+    pub date_sources: Vec<DateSource>,
+    /// Only use the given source to get the date of an email, overriding
+    /// --date-sources.
+    #[clap(long, value_enum, conflicts_with = "date_sources")]
+    pub force_date_source: Option<DateSource>,
+    /// Only pack emails older than the given age, e.g. `36h`, `90d` or
+    /// `12w`.
+    #[clap(long, value_parser = parse_age)]
+    pub older_than: Option<Duration>,
+    /// What the age of an email is based on. Emails without a date use the
+    /// file mtime either way.
+    #[clap(long, value_enum, default_value = "date", requires = "older_than")]
+    pub age_basis: AgeBasis,
+    /// Don't pack emails into the archive of the current period, so that
+    /// it isn't rewritten as it keeps growing.
+    #[clap(long)]
+    pub skip_current: bool,
+    /// How long a period of time each archive covers.
+    #[clap(short, long, value_enum, default_value = "monthly")]
+    pub granularity: Granularity,
+    /// Template of archive names, which overrides --granularity. It can
+    /// contain placeholders {folder}, {year}, {quarter}, {month}, {day},
+    /// {week_year} and {week}, as well as strftime-like specifiers like %Y.
+    /// A `/` creates nested directories, e.g. `{year}/{month}`.
+    #[clap(long, conflicts_with = "granularity")]
+    pub name_template: Option<NameTemplate>,
+    /// Name of the archive for emails without a date. It can contain the
+    /// {folder} placeholder.
+    #[clap(long, default_value = "unknown", value_parser = parse_unknown_name)]
+    pub unknown_name: NameTemplate,
+    /// The time zone in which emails are put into archives. It can be `utc`,
+    /// `local`, `sender` which uses the offset in the date of each email, a
+    /// fixed offset like `-04:00`, or an IANA time zone like `Asia/Tokyo`.
+    #[clap(long = "timezone", default_value = "utc")]
+    pub time_zone: BucketTimeZone,
+    /// Format of archives. Existing archives in the other format are
+    /// converted when emails are added to them.
+    #[clap(long, value_enum, default_value = "tar")]
+    pub format: ArchiveFormat,
+    /// Compression format of archives. Existing archives in other formats
+    /// are converted when emails are added to them.
+    #[clap(short, long, value_enum, default_value = "xz")]
+    pub compression: Codec,
+    /// Compression level, which defaults to 9 for xz, gzip and bzip2, and to
+    /// 3 for zstd, whose higher levels are much slower.
+    #[clap(long = "level")]
+    level: Option<u32>,
+    /// The compression level, resolved from --level and --compression.
+    #[clap(skip)]
+    pub compression_level: u32,
+    #[clap(flatten)]
+    pub threading: ThreadArgs,
+    /// Append new emails to existing archives as a new compressed stream,
+    /// instead of recompressing the whole archive. Use the `compact`
+    /// subcommand to merge the streams later.
+    #[clap(long)]
+    pub append: bool,
+    /// Unpack all existing archives and pack their emails again, which is
+    /// useful after changing the granularity.
+    #[clap(long)]
+    pub rebucket: bool,
+    /// Keep emails in the maildir after packing them. Emails which are in
+    /// the archives already with identical content are skipped.
     #[clap(short, long)]
+    pub keep: bool,
+    /// Read each archive back after writing it, and check every entry
+    /// against the existing archive and the emails before removing them.
+    #[clap(long)]
+    pub verify: bool,
+    /// Don't flush archives and directories to disk, which is faster but
+    /// may lose both archives and emails on a power failure.
+    #[clap(long)]
+    pub no_sync: bool,
+    /// Wait for another run on the same maildir to finish, instead of
+    /// failing immediately. It also applies to subcommands which modify
+    /// archives.
+    #[clap(long, global = true)]
+    pub wait: bool,
+    /// Print which archives would be created or updated with how many
+    /// emails, without modifying any file.
+    #[clap(short = 'n', long, conflicts_with = "rebucket")]
+    pub dry_run: bool,
+    /// Suppress any progress output if set. It also applies to subcommands.
+    #[clap(short, long, global = true)]
     pub quiet: bool,
+    /// Report where the date of each email comes from.
+    #[clap(short, long, conflicts_with = "quiet")]
+    pub verbose: bool,
 }
 
 impl Args {
     pub fn parse_args() -> Self {
         let mut result: Self = Self::parse();
+        result.maildir = match &result.command {
+            Some(Command::List(list_args)) => list_args.maildir.clone(),
+            Some(Command::Unpack(unpack_args)) => unpack_args.maildir.clone(),
+            Some(Command::Search(search_args)) => search_args.maildir.clone(),
+            Some(Command::Index(index_args)) => index_args.maildir.clone(),
+            Some(Command::Compact(compact_args)) => compact_args.maildir.clone(),
+            None => result.maildir_arg.clone().unwrap(),
+        };
         result.packed_dir = result.maildir.join("packed");
+        let (min, max) = result.compression.level_range();
+        result.compression_level = match result.level {
+            Some(level) if !(min..=max).contains(&level) => Self::command()
+                .error(
+                    ErrorKind::ValueValidation,
+                    format!("compression level must be within {}..={}", min, max),
+                )
+                .exit(),
+            Some(level) => level,
+            None => result.compression.default_level(),
+        };
+        if let Some(source) = result.force_date_source {
+            result.date_sources = vec![source];
+        }
         result
     }
+
+    /// Whether emails are imported from mbox files or MH folders.
+    pub fn has_imports(&self) -> bool {
+        !self.mbox.is_empty() || !self.mh.is_empty()
+    }
+
+    /// Kind of archives to write, from --format and --compression.
+    pub fn archive_kind(&self) -> ArchiveKind {
+        ArchiveKind {
+            format: self.format,
+            codec: self.compression,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// List archives in maildir/packed with their number of emails, sizes
+    /// and date ranges.
+    List(ListArgs),
+    /// Restore emails from archives in maildir/packed into a maildir.
+    Unpack(UnpackArgs),
+    /// Search emails in archives in maildir/packed by their headers and
+    /// dates.
+    Search(SearchArgs),
+    /// Rebuild missing or stale indexes of archives in maildir/packed.
+    Index(IndexArgs),
+    /// Recompress archives in maildir/packed into a single stream, merging
+    /// the streams appended by --append.
+    Compact(CompactArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ListArgs {
+    /// Path to the maildir.
+    pub maildir: PathBuf,
+    /// Also list every email in the archives with its size and hash.
+    #[clap(short, long)]
+    pub entries: bool,
+    /// Print in JSON format.
+    #[clap(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct UnpackArgs {
+    /// Path to the maildir.
+    pub maildir: PathBuf,
+    /// Archives to unpack, with or without the suffix, e.g. `2017-06` or
+    /// `Lists.rust/2017-06.tar.xz`. All archives are unpacked if none is
+    /// given.
+    pub archives: Vec<String>,
+    /// Only restore emails whose name matches the pattern, where `*` matches
+    /// any characters and `?` matches a single character. It can be given
+    /// multiple times.
+    #[clap(short, long = "entry", value_name = "PATTERN")]
+    pub entries: Vec<String>,
+    /// The maildir to restore emails into, which defaults to the maildir
+    /// itself.
+    #[clap(long, value_name = "MAILDIR")]
+    pub to: Option<PathBuf>,
+    /// Restore emails into maildir/cur instead of maildir/new.
+    #[clap(long)]
+    pub cur: bool,
+    /// Remove restored emails from the archives.
+    #[clap(long)]
+    pub remove: bool,
+    /// Compression level of archives rewritten by --remove, which defaults
+    /// to 9 for xz, gzip and bzip2, and to 3 for zstd, according to the
+    /// format of each archive.
+    #[clap(long)]
+    pub level: Option<u32>,
+    #[clap(flatten)]
+    pub threading: ThreadArgs,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct SearchArgs {
+    /// Path to the maildir.
+    pub maildir: PathBuf,
+    /// Match emails whose From header contains the text. Header values are
+    /// matched case-insensitively as they are, without decoding.
+    #[clap(long)]
+    pub from: Option<String>,
+    /// Match emails whose To header contains the text.
+    #[clap(long)]
+    pub to: Option<String>,
+    /// Match emails whose Subject header contains the text.
+    #[clap(long)]
+    pub subject: Option<String>,
+    /// Match emails whose Message-ID header contains the text.
+    #[clap(long)]
+    pub message_id: Option<String>,
+    /// Match emails dated on or after the date in UTC, e.g. 2017-06-01.
+    #[clap(long)]
+    pub since: Option<NaiveDate>,
+    /// Match emails dated on or before the date in UTC, e.g. 2017-06-30.
+    #[clap(long)]
+    pub until: Option<NaiveDate>,
+    /// Extract matching emails into the directory.
+    #[clap(long, value_name = "DIR")]
+    pub extract: Option<PathBuf>,
+    /// Print matching emails in mboxrd format instead of their names.
+    #[clap(long, conflicts_with = "extract")]
+    pub mbox: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct IndexArgs {
+    /// Path to the maildir.
+    pub maildir: PathBuf,
+    /// Rebuild indexes of all archives, even if they look up to date.
+    #[clap(long)]
+    pub force: bool,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct CompactArgs {
+    /// Path to the maildir.
+    pub maildir: PathBuf,
+    /// Archives to compact, with or without the suffix. All archives are
+    /// compacted if none is given.
+    pub archives: Vec<String>,
+    /// Compression level, which defaults to 9 for xz, gzip and bzip2, and to
+    /// 3 for zstd, according to the format of each archive.
+    #[clap(long)]
+    pub level: Option<u32>,
+    #[clap(flatten)]
+    pub threading: ThreadArgs,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ThreadArgs {
+    /// Number of threads to compress each xz archive with, or 0 for as many
+    /// as CPUs. Archives are packed one at a time unless it is 1, and each
+    /// thread needs about three times the block size of memory.
+    #[clap(long, default_value = "1")]
+    pub threads: u32,
+    /// Size of blocks which threads compress independently, e.g. `4M`, or
+    /// three times the dictionary size of the level by default. xz archives
+    /// are split into blocks of this size even with a single thread.
+    #[clap(long, value_parser = parse_size)]
+    pub block_size: Option<u64>,
+}
+
+fn parse_unknown_name(s: &str) -> Result<NameTemplate, String> {
+    let template: NameTemplate = s.parse()?;
+    if template.uses_date() {
+        return Err("it cannot use date".to_string());
+    }
+    Ok(template)
+}
+
+fn parse_size(s: &str) -> Result<u64, String> {
+    let pos = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(pos);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| "it must be a number optionally followed by a unit".to_string())?;
+    let unit = match unit {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return Err("unit must be K, M or G".to_string()),
+    };
+    number
+        .checked_mul(unit)
+        .ok_or_else(|| "it is too large".to_string())
+}
+
+fn parse_age(s: &str) -> Result<Duration, String> {
+    let pos = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(pos);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| "it must be a number followed by a unit".to_string())?;
+    let unit = match unit {
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err("unit must be h, d or w".to_string()),
+    };
+    number
+        .checked_mul(unit)
+        .and_then(|secs| Duration::from_std(std::time::Duration::from_secs(secs)).ok())
+        .ok_or_else(|| "it is too large".to_string())
 }
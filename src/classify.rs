@@ -1,20 +1,131 @@
-use chrono::{DateTime, FixedOffset};
+use crate::args::Args;
+use crate::collect::Email;
+use crate::folder::Folder;
+use crate::template::NameTemplate;
+use chrono::{DateTime, FixedOffset, Local, NaiveDateTime};
+use chrono_tz::Tz;
+use clap::ValueEnum;
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::str::FromStr;
 
-fn get_archive_name(dt: &Option<DateTime<FixedOffset>>) -> String {
-    dt.map(|dt| dt.naive_utc().format("%Y-%m").to_string())
-        .unwrap_or_else(|| "unknown".to_string())
+/// How long a period of time each archive covers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Granularity {
+    /// One archive per year, e.g. `2017`.
+    Yearly,
+    /// One archive per quarter, e.g. `2017-Q2`.
+    Quarterly,
+    /// One archive per month, e.g. `2017-06`.
+    Monthly,
+    /// One archive per ISO 8601 week, e.g. `2017-W26`.
+    Weekly,
+    /// One archive per day, e.g. `2017-06-30`.
+    Daily,
+}
+
+impl Granularity {
+    /// The name template of archives with this granularity.
+    pub fn template(self) -> NameTemplate {
+        let template = match self {
+            Granularity::Yearly => "{year}",
+            Granularity::Quarterly => "{year}-Q{quarter}",
+            Granularity::Monthly => "{year}-{month}",
+            Granularity::Weekly => "{week_year}-W{week}",
+            Granularity::Daily => "{year}-{month}-{day}",
+        };
+        template.parse().unwrap()
+    }
+}
+
+/// The time zone in which emails are put into buckets.
+#[derive(Clone, Copy, Debug)]
+pub enum BucketTimeZone {
+    Utc,
+    /// The local time zone of the system.
+    Local,
+    /// The time zone of the sender, i.e. the offset in the date of the email.
+    Sender,
+    Fixed(FixedOffset),
+    /// A time zone in the IANA time zone database, e.g. `America/New_York`.
+    Named(Tz),
+}
+
+impl BucketTimeZone {
+    fn to_naive(self, dt: &DateTime<FixedOffset>) -> NaiveDateTime {
+        match self {
+            BucketTimeZone::Utc => dt.naive_utc(),
+            BucketTimeZone::Local => dt.with_timezone(&Local).naive_local(),
+            BucketTimeZone::Sender => dt.naive_local(),
+            BucketTimeZone::Fixed(offset) => dt.with_timezone(&offset).naive_local(),
+            BucketTimeZone::Named(tz) => dt.with_timezone(&tz).naive_local(),
+        }
+    }
+}
+
+impl FromStr for BucketTimeZone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "utc" | "UTC" => BucketTimeZone::Utc,
+            "local" => BucketTimeZone::Local,
+            "sender" => BucketTimeZone::Sender,
+            _ if s.starts_with(['+', '-']) => {
+                BucketTimeZone::Fixed(s.parse().map_err(|_| format!("invalid offset {}", s))?)
+            }
+            _ => BucketTimeZone::Named(s.parse()?),
+        })
+    }
+}
+
+fn get_archive_name(
+    dt: &Option<DateTime<FixedOffset>>,
+    time_zone: BucketTimeZone,
+    template: &NameTemplate,
+    unknown_name: &NameTemplate,
+    folder_name: &str,
+) -> String {
+    match dt {
+        Some(dt) => template.expand(Some(&time_zone.to_naive(dt)), folder_name),
+        None => unknown_name.expand(None, folder_name),
+    }
 }
 
 pub fn classify_emails(
-    list: Vec<(PathBuf, Option<DateTime<FixedOffset>>)>,
-) -> HashMap<String, Vec<PathBuf>> {
+    args: &Args,
+    folder: &Folder,
+    list: Vec<Email>,
+) -> HashMap<String, Vec<Email>> {
+    let template = args
+        .name_template
+        .clone()
+        .unwrap_or_else(|| args.granularity.template());
+    let current = args.skip_current.then(|| {
+        let now = Local::now().fixed_offset();
+        get_archive_name(
+            &Some(now),
+            args.time_zone,
+            &template,
+            &args.unknown_name,
+            &folder.name,
+        )
+    });
+    let staging_dir = folder.staging_dir();
     let mut map = HashMap::new();
-    for (path, dt) in list {
-        map.entry(get_archive_name(&dt))
-            .or_insert_with(Vec::new)
-            .push(path);
+    for email in list {
+        let name = get_archive_name(
+            &email.datetime,
+            args.time_zone,
+            &template,
+            &args.unknown_name,
+            &folder.name,
+        );
+        // Emails unpacked for re-bucketing are always packed again, while
+        // imported messages are skipped as emails in the maildir are.
+        if current.as_ref() == Some(&name) && !email.path.starts_with(&staging_dir) {
+            continue;
+        }
+        map.entry(name).or_insert_with(Vec::new).push(email);
     }
     map
 }
@@ -25,11 +136,31 @@ mod tests {
 
     use chrono::DateTime;
 
+    fn get_name(dt: &Option<DateTime<FixedOffset>>, granularity: Granularity) -> String {
+        let unknown_name = "unknown".parse().unwrap();
+        get_archive_name(
+            dt,
+            BucketTimeZone::Utc,
+            &granularity.template(),
+            &unknown_name,
+            "",
+        )
+    }
+
     #[test]
     fn test_get_archive_name() {
         fn assert_name(time: &str, expected: &str) {
             let dt = Some(DateTime::parse_from_rfc3339(time).unwrap());
-            assert_eq!(get_archive_name(&dt), expected);
+            assert_eq!(get_name(&dt, Granularity::Monthly), expected);
+        }
+
+        fn assert_name_in(time: &str, time_zone: &str, expected: &str) {
+            let dt = Some(DateTime::parse_from_rfc3339(time).unwrap());
+            let time_zone = time_zone.parse().unwrap();
+            let template = Granularity::Monthly.template();
+            let unknown_name = "unknown".parse().unwrap();
+            let name = get_archive_name(&dt, time_zone, &template, &unknown_name, "");
+            assert_eq!(name, expected);
         }
 
         assert_name("2017-06-30T20:00:00+04:00", "2017-06");
@@ -40,6 +171,82 @@ mod tests {
         assert_name("2017-07-01T03:59:59+00:00", "2017-07");
         assert_name("2017-07-01T03:59:59-04:00", "2017-07");
 
-        assert_eq!(get_archive_name(&None), "unknown");
+        assert_eq!(get_name(&None, Granularity::Monthly), "unknown");
+
+        assert_name_in("2017-06-30T20:00:00-04:00", "utc", "2017-07");
+        assert_name_in("2017-06-30T20:00:00+00:00", "UTC", "2017-06");
+
+        assert_name_in("2017-06-30T20:00:00+04:00", "sender", "2017-06");
+        assert_name_in("2017-06-30T20:00:00-04:00", "sender", "2017-06");
+        assert_name_in("2017-07-01T03:59:59+04:00", "sender", "2017-07");
+        assert_name_in("2017-07-01T03:59:59-04:00", "sender", "2017-07");
+
+        assert_name_in("2017-06-30T20:00:00-04:00", "-04:00", "2017-06");
+        assert_name_in("2017-07-01T00:00:00+00:00", "-0400", "2017-06");
+        assert_name_in("2017-06-30T20:00:00+00:00", "+04:00", "2017-07");
+        assert_name_in("2017-06-30T20:00:00+00:00", "+03:59", "2017-06");
+
+        // New York is at UTC-4 in summer with DST, and UTC-5 in winter.
+        assert_name_in("2017-07-01T03:59:59+00:00", "America/New_York", "2017-06");
+        assert_name_in("2017-07-01T04:00:00+00:00", "America/New_York", "2017-07");
+        assert_name_in("2017-01-01T04:59:59+00:00", "America/New_York", "2016-12");
+        assert_name_in("2017-01-01T05:00:00+00:00", "America/New_York", "2017-01");
+    }
+
+    #[test]
+    fn test_get_archive_name_granularity() {
+        fn assert_name(time: &str, granularity: Granularity, expected: &str) {
+            let dt = Some(DateTime::parse_from_rfc3339(time).unwrap());
+            assert_eq!(get_name(&dt, granularity), expected);
+        }
+
+        assert_name("2017-06-30T20:00:00+00:00", Granularity::Yearly, "2017");
+        assert_name("2017-12-31T20:00:00-04:00", Granularity::Yearly, "2018");
+
+        assert_name(
+            "2017-03-31T20:00:00+00:00",
+            Granularity::Quarterly,
+            "2017-Q1",
+        );
+        assert_name(
+            "2017-04-01T00:00:00+00:00",
+            Granularity::Quarterly,
+            "2017-Q2",
+        );
+        assert_name(
+            "2017-09-30T23:59:59+00:00",
+            Granularity::Quarterly,
+            "2017-Q3",
+        );
+        assert_name(
+            "2017-12-31T23:59:59+00:00",
+            Granularity::Quarterly,
+            "2017-Q4",
+        );
+
+        // ISO week-numbering year can differ from the calendar year.
+        assert_name("2017-06-30T20:00:00+00:00", Granularity::Weekly, "2017-W26");
+        assert_name("2018-12-31T00:00:00+00:00", Granularity::Weekly, "2019-W01");
+        assert_name("2021-01-03T00:00:00+00:00", Granularity::Weekly, "2020-W53");
+
+        assert_name(
+            "2017-06-30T20:00:00+00:00",
+            Granularity::Daily,
+            "2017-06-30",
+        );
+        assert_name(
+            "2017-06-30T20:00:00-04:00",
+            Granularity::Daily,
+            "2017-07-01",
+        );
+
+        assert_eq!(get_name(&None, Granularity::Yearly), "unknown");
+    }
+
+    #[test]
+    fn test_parse_time_zone() {
+        assert!("Nowhere/Special".parse::<BucketTimeZone>().is_err());
+        assert!("+25:00".parse::<BucketTimeZone>().is_err());
+        assert!("+04".parse::<BucketTimeZone>().is_err());
     }
 }
@@ -1,12 +1,98 @@
+use crate::archive::Entry;
 use crate::args::Args;
-use crate::datetime::parse_datetime;
-use crate::utils;
+use crate::datetime::{parse_datetime, parse_mbox_datetime};
+use crate::folder::Folder;
+use crate::mbox::MboxReader;
+use crate::mh;
+use crate::utils::{self, get_file_name};
+use crate::verify::{hash_bytes, to_hex, HashResult};
 use anyhow::{Context, Result};
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::ValueEnum;
 use rayon::prelude::*;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, ErrorKind};
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Where the date of an email can come from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum DateSource {
+    /// The `Date` header.
+    Date,
+    /// The date after the `;` in the top-most `Received` header.
+    Received,
+    /// The top-most `Resent-Date` header.
+    ResentDate,
+    /// The `From ` line of mbox format.
+    FromLine,
+    /// The delivery timestamp at the start of the maildir file name.
+    FileName,
+    /// The modification time of the file.
+    Mtime,
+}
+
+/// What the age of an email is based on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum AgeBasis {
+    /// The date of the email from the date sources.
+    Date,
+    /// The modification time of the file.
+    Mtime,
+}
+
+impl fmt::Display for DateSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DateSource::Date => "Date header",
+            DateSource::Received => "Received header",
+            DateSource::ResentDate => "Resent-Date header",
+            DateSource::FromLine => "From line",
+            DateSource::FileName => "file name",
+            DateSource::Mtime => "file mtime",
+        })
+    }
+}
+
+/// An email to be packed.
+pub struct Email {
+    pub path: PathBuf,
+    /// Digest of a message imported in memory by a dry run, which has no
+    /// file at the path.
+    pub imported: Option<ImportedDigest>,
+    pub datetime: Option<DateTime<FixedOffset>>,
+    /// Where the datetime comes from.
+    pub date_source: Option<DateSource>,
+}
+
+/// Header section of an email.
+#[derive(Default)]
+pub struct Headers {
+    /// The content after `From ` if the email starts with a mbox `From ` line.
+    from_line: Option<Vec<u8>>,
+    /// Header fields as (name, value) pairs in their original order, with
+    /// folded lines unfolded.
+    fields: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Headers {
+    /// Returns the content after `From ` if the email starts with a mbox
+    /// `From ` line.
+    pub fn mbox_from_line(&self) -> Option<&[u8]> {
+        self.from_line.as_deref()
+    }
+
+    /// Returns the value of the first field with the given name.
+    pub fn get(&self, name: &[u8]) -> Option<&[u8]> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| &v[..])
+    }
+}
 
 /// Whether the given byte is a WSP as defined in RFC 5234 Appendix B.1
 /// https://tools.ietf.org/html/rfc5234#appendix-B.1
@@ -14,39 +100,329 @@ fn is_wsp(b: u8) -> bool {
     b == 0x20 || b == 0x09
 }
 
-fn get_datetime_from_email(file: &Path) -> Result<Option<DateTime<FixedOffset>>> {
-    const DATE_HEADER: &[u8] = b"date: ";
-    let file =
-        File::open(file).with_context(|| format!("failed to open {:?}", file.file_name()))?;
-    let reader = BufReader::new(file);
-    let mut date: Option<Vec<u8>> = None;
-    for line in reader.split(b'\n') {
+pub fn read_headers(reader: impl BufRead) -> io::Result<Headers> {
+    const FROM_LINE: &[u8] = b"From ";
+    let mut headers = Headers::default();
+    for (i, line) in reader.split(b'\n').enumerate() {
         let line = line?;
-        if line.is_empty() {
+        if line.is_empty() || line == b"\r" {
             break;
         }
-        if date.is_some() {
+        if i == 0 && line.starts_with(FROM_LINE) {
+            // A header field named "From" may have whitespaces before the
+            // colon in obsolete syntax, which is not a `From ` line.
+            let rest = &line[FROM_LINE.len()..];
+            if rest.iter().find(|&&b| !is_wsp(b)) != Some(&b':') {
+                headers.from_line = Some(rest.to_vec());
+                continue;
+            }
+        }
+        if is_wsp(line[0]) {
             // Line breaks can be folded with whitespaces.
-            if !is_wsp(line[0]) {
-                break;
+            if let Some((_, value)) = headers.fields.last_mut() {
+                value.extend(line);
             }
-            date.as_mut().unwrap().extend(line);
+            continue;
+        }
+        if let Some(pos) = line.iter().position(|&b| b == b':') {
+            let value = &line[pos + 1..];
+            let start = value
+                .iter()
+                .position(|&b| !is_wsp(b))
+                .unwrap_or(value.len());
+            let value = value[start..].to_vec();
+            let mut name = line;
+            name.truncate(pos);
+            headers.fields.push((name, value));
+        }
+    }
+    Ok(headers)
+}
+
+fn get_datetime_from_header(
+    headers: &Headers,
+    source: DateSource,
+) -> Option<DateTime<FixedOffset>> {
+    match source {
+        DateSource::Date => headers.get(b"date").and_then(parse_datetime),
+        DateSource::Received => headers.get(b"received").and_then(|value| {
+            let pos = value.iter().rposition(|&b| b == b';')?;
+            parse_datetime(value[pos + 1..].trim_ascii_start())
+        }),
+        DateSource::ResentDate => headers.get(b"resent-date").and_then(parse_datetime),
+        DateSource::FromLine => headers.from_line.as_deref().and_then(parse_mbox_datetime),
+        // Not from headers.
+        DateSource::FileName | DateSource::Mtime => None,
+    }
+}
+
+/// Gets the delivery time from the file name, which starts with a timestamp
+/// in maildir, e.g. `1538824850.M951087P44546Q42R...`.
+/// https://cr.yp.to/proto/maildir.html
+fn get_datetime_from_file_name(path: &Path) -> Option<DateTime<FixedOffset>> {
+    let file_name = path.file_name()?.to_str()?;
+    let (timestamp, _) = file_name.split_once('.')?;
+    if timestamp.is_empty() || !timestamp.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let dt = DateTime::from_timestamp(timestamp.parse().ok()?, 0)?;
+    Some(dt.fixed_offset())
+}
+
+/// Gets the date of an email in an archive from its headers, falling back to
+/// its file name.
+pub fn get_datetime_from_archived(path: &Path, headers: &Headers) -> Option<DateTime<FixedOffset>> {
+    const SOURCES: [DateSource; 4] = [
+        DateSource::Date,
+        DateSource::Received,
+        DateSource::ResentDate,
+        DateSource::FromLine,
+    ];
+    SOURCES
+        .iter()
+        .find_map(|&source| get_datetime_from_header(headers, source))
+        .or_else(|| get_datetime_from_file_name(path))
+}
+
+/// Where an email to be packed comes from.
+enum Origin {
+    /// maildir/new or maildir/cur.
+    Maildir,
+    /// The staging directory, where emails unpacked from existing archives
+    /// for re-bucketing have lost their mtime.
+    Staged,
+    /// An mbox file or an MH folder, whose file mtime is given.
+    Imported(SystemTime),
+}
+
+impl Origin {
+    /// Returns the mtime of the email at the given path, if it is known.
+    fn get_mtime(&self, path: &Path) -> Result<Option<DateTime<FixedOffset>>> {
+        let mtime = match self {
+            Origin::Maildir => fs::metadata(path)?.modified()?,
+            Origin::Staged => return Ok(None),
+            Origin::Imported(mtime) => *mtime,
+        };
+        Ok(Some(DateTime::<Utc>::from(mtime).fixed_offset()))
+    }
+}
+
+/// Gets the date of the email from the first of the sources which supplies
+/// one. The headers are read from the given content if the email has no
+/// file.
+fn get_datetime_from_email(
+    path: &Path,
+    content: Option<&[u8]>,
+    origin: &Origin,
+    sources: &[DateSource],
+) -> Result<Option<(DateTime<FixedOffset>, DateSource)>> {
+    let mut headers = None;
+    for &source in sources {
+        let dt = match source {
+            DateSource::FileName => get_datetime_from_file_name(path),
+            DateSource::Mtime => origin.get_mtime(path)?,
+            _ => {
+                if headers.is_none() {
+                    headers = Some(match content {
+                        Some(content) => read_headers(content)?,
+                        None => {
+                            let file = File::open(path).with_context(|| {
+                                format!("failed to open {:?}", path.file_name())
+                            })?;
+                            read_headers(BufReader::new(file))?
+                        }
+                    });
+                }
+                get_datetime_from_header(headers.as_ref().unwrap(), source)
+            }
+        };
+        if let Some(dt) = dt {
+            return Ok(Some((dt, source)));
+        }
+    }
+    Ok(None)
+}
+
+/// What a dry run keeps of a message imported in memory, instead of writing
+/// it into the import directory, which is all that planning needs.
+pub struct ImportedDigest {
+    /// Name of the message as an entry of archives in the packing format.
+    pub entry_name: OsString,
+    pub hash: HashResult,
+    datetime: Option<(DateTime<FixedOffset>, DateSource)>,
+}
+
+/// A message imported from an mbox file or an MH folder.
+pub struct ImportedEmail {
+    path: PathBuf,
+    /// Digest of the message if it is imported in memory by a dry run.
+    imported: Option<ImportedDigest>,
+    /// The mtime of the file which the message comes from.
+    mtime: SystemTime,
+}
+
+/// Collects messages imported from mbox files and MH folders, which are
+/// written into the import directory of the folder, so that they are packed
+/// along with other emails. Each message is named after the hash of its
+/// content with the given prefix, which makes importing the same message
+/// again idempotent.
+struct Importer<'a> {
+    args: &'a Args,
+    import_dir: PathBuf,
+    prefix: &'a str,
+    emails: Vec<ImportedEmail>,
+    names: HashSet<String>,
+}
+
+impl<'a> Importer<'a> {
+    fn new(args: &'a Args, folder: &Folder, prefix: &'a str) -> io::Result<Self> {
+        let import_dir = folder.import_dir();
+        if !args.dry_run {
+            fs::create_dir_all(&import_dir)?;
+        }
+        Ok(Importer {
+            args,
+            import_dir,
+            prefix,
+            emails: vec![],
+            names: HashSet::new(),
+        })
+    }
+
+    fn import(&mut self, message: Vec<u8>, mtime: SystemTime) -> Result<()> {
+        let name = format!("{}-{}", self.prefix, &to_hex(&hash_bytes(&message))[..40]);
+        let path = self.import_dir.join(&name);
+        // The same message may appear more than once.
+        if !self.names.insert(name.clone()) {
+            return Ok(());
+        }
+        let imported = if self.args.dry_run {
+            let datetime = get_datetime_from_email(
+                &path,
+                Some(&message),
+                &Origin::Imported(mtime),
+                &self.args.date_sources,
+            )?;
+            let mut entry =
+                Entry::new(PathBuf::from(name), message).into_format(self.args.format)?;
+            let hash = entry.digest()?.hash;
+            Some(ImportedDigest {
+                entry_name: entry.path.into_os_string(),
+                hash,
+                datetime,
+            })
         } else {
-            if line.len() <= DATE_HEADER.len()
-                || !line[..DATE_HEADER.len()].eq_ignore_ascii_case(DATE_HEADER)
-            {
+            if !path.exists() {
+                // Write into a hidden file first, so that a partially
+                // written message is never picked up.
+                let part_path = self.import_dir.join(format!(".{}.part", name));
+                fs::write(&part_path, message)?;
+                fs::rename(&part_path, &path)?;
+            }
+            None
+        };
+        self.emails.push(ImportedEmail {
+            path,
+            imported,
+            mtime,
+        });
+        Ok(())
+    }
+}
+
+/// Splits the mbox files into messages, which are dated by the mtime of the
+/// mbox file if needed. See `Importer`.
+pub fn import_mbox_files(args: &Args, folder: &Folder) -> Result<Vec<ImportedEmail>> {
+    let mut importer = Importer::new(args, folder, "mbox")?;
+    for path in &args.mbox {
+        let file =
+            File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        let mtime = file.metadata()?.modified()?;
+        for message in MboxReader::new(BufReader::new(file), args.mbox_format) {
+            let message = message.with_context(|| format!("failed to read {}", path.display()))?;
+            importer.import(message, mtime)?;
+        }
+    }
+    Ok(importer.emails)
+}
+
+/// Reads messages of the MH folders, which are filtered by the sequences
+/// given by --mh-sequence and --mh-exclude-sequence. See `Importer`.
+pub fn import_mh_folders(args: &Args, folder: &Folder) -> Result<Vec<ImportedEmail>> {
+    let mut importer = Importer::new(args, folder, "mh")?;
+    for dir in &args.mh {
+        let messages =
+            mh::list_messages(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+        let sequences = mh::read_sequences(dir)
+            .with_context(|| format!("failed to read sequences of {}", dir.display()))?;
+        let in_sequences = |names: &[String], number: u32| {
+            names
+                .iter()
+                .any(|name| sequences.get(name).is_some_and(|seq| seq.contains(&number)))
+        };
+        for (number, path) in messages {
+            if !args.mh_sequence.is_empty() && !in_sequences(&args.mh_sequence, number) {
+                continue;
+            }
+            if in_sequences(&args.mh_exclude_sequence, number) {
                 continue;
             }
-            date = Some(line[DATE_HEADER.len()..].to_vec());
+            let message =
+                fs::read(&path).with_context(|| format!("failed to read {}", path.display()))?;
+            importer.import(message, fs::metadata(&path)?.modified()?)?;
         }
     }
-    Ok(date.as_ref().and_then(|dt| parse_datetime(dt)))
+    Ok(importer.emails)
 }
 
-pub fn list_emails(args: &Args) -> Result<Vec<(PathBuf, Option<DateTime<FixedOffset>>)>> {
+/// Removes the import directory of the folder. Messages left there are
+/// not packed, e.g. because they are too new, and are imported again from
+/// their sources by later runs.
+pub fn remove_import_dir(folder: &Folder) {
+    // It's okay if it fails, since it may not exist.
+    let _ = fs::remove_dir_all(folder.import_dir());
+}
+
+/// Lists emails to be packed in the maildir and the staging directory of the
+/// folder, along with the given imported messages. Emails newer than
+/// --older-than are skipped, except for those unpacked for re-bucketing.
+pub fn list_emails(
+    args: &Args,
+    folder: &Folder,
+    imported: Vec<ImportedEmail>,
+) -> Result<Vec<Email>> {
+    let mut dirs = vec!["new"];
+    if args.include_cur {
+        dirs.push("cur");
+    }
+    // A subfolder may have only one of new and cur, and only mbox files and
+    // MH folders may be packed into a root directory which isn't a maildir.
+    let optional = !folder.name.is_empty() || args.has_imports();
     let mut files = vec![];
-    for entry in fs::read_dir(&args.maildir.join("new"))? {
-        files.push(entry?.path());
+    for dir in dirs {
+        let entries = match fs::read_dir(folder.path.join(dir)) {
+            Ok(entries) => entries,
+            Err(e) if optional && e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            files.push((entry?.path(), None, Origin::Maildir));
+        }
+    }
+    // Emails unpacked from existing archives for re-bucketing, which may
+    // also be left over from an interrupted run.
+    let staging_dir = folder.staging_dir();
+    if staging_dir.is_dir() {
+        for entry in fs::read_dir(staging_dir)? {
+            let path = entry?.path();
+            // Skip partially unpacked files.
+            if !get_file_name(&path).to_string_lossy().starts_with('.') {
+                files.push((path, None, Origin::Staged));
+            }
+        }
+    }
+    for email in imported {
+        files.push((email.path, email.imported, Origin::Imported(email.mtime)));
     }
 
     // There is no email, just return.
@@ -54,19 +430,168 @@ pub fn list_emails(args: &Args) -> Result<Vec<(PathBuf, Option<DateTime<FixedOff
         return Ok(vec![]);
     }
 
+    let cutoff = args.older_than.map(|age| Utc::now() - age);
     let progress = utils::create_progress_bar(args, files.len());
     let result = files
         .into_par_iter()
         .enumerate()
-        .map(|(i, path)| {
-            let dt = get_datetime_from_email(&path).unwrap_or(None);
+        .filter_map(|(i, (path, imported, origin))| {
+            let dt = match &imported {
+                Some(imported) => imported.datetime,
+                None => get_datetime_from_email(&path, None, &origin, &args.date_sources)
+                    .unwrap_or(None),
+            };
             if i % 128 == 127 {
                 progress.inc(128);
             }
-            (path, dt)
+            let email = Email {
+                path,
+                imported,
+                datetime: dt.map(|(dt, _)| dt),
+                date_source: dt.map(|(_, source)| source),
+            };
+            // Emails unpacked for re-bucketing are always packed again,
+            // while imported messages are subject to the cutoff.
+            match (cutoff, &origin) {
+                (Some(_), Origin::Staged) | (None, _) => Some(email),
+                (Some(cutoff), _) => {
+                    is_older_than(&email, &origin, args.age_basis, cutoff).then_some(email)
+                }
+            }
         })
         .collect();
     progress.finish_and_clear();
 
     Ok(result)
 }
+
+fn is_older_than(email: &Email, origin: &Origin, basis: AgeBasis, cutoff: DateTime<Utc>) -> bool {
+    let dt = match (basis, email.datetime) {
+        (AgeBasis::Date, Some(dt)) => dt,
+        _ => match origin.get_mtime(&email.path) {
+            Ok(Some(dt)) => dt,
+            _ => return false,
+        },
+    };
+    dt < cutoff
+}
+
+/// Reports where the dates of the emails come from. The source of each email
+/// is listed if verbose is set, otherwise only the numbers are reported.
+pub fn report_date_sources(args: &Args, list: &[Email]) {
+    if args.quiet {
+        return;
+    }
+    let mut counts = BTreeMap::new();
+    for email in list {
+        *counts.entry(email.date_source).or_insert(0) += 1;
+        if args.verbose {
+            match (email.datetime, email.date_source) {
+                (Some(dt), Some(source)) => {
+                    eprintln!(
+                        "{}: {} from {}",
+                        email.path.display(),
+                        dt.to_rfc2822(),
+                        source
+                    )
+                }
+                _ => eprintln!("{}: no date found", email.path.display()),
+            }
+        }
+    }
+    let unknown = counts.remove(&None);
+    for (source, count) in counts {
+        eprintln!("{} email(s) dated by {}", count, source.unwrap());
+    }
+    if let Some(count) = unknown {
+        eprintln!("{} email(s) without date", count);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALL_SOURCES: &[DateSource] = &[
+        DateSource::Date,
+        DateSource::Received,
+        DateSource::ResentDate,
+        DateSource::FromLine,
+    ];
+
+    fn get_datetime(email: &[u8], sources: &[DateSource]) -> Option<(String, DateSource)> {
+        let headers = read_headers(email).unwrap();
+        sources.iter().find_map(|&source| {
+            get_datetime_from_header(&headers, source).map(|dt| (dt.to_rfc3339(), source))
+        })
+    }
+
+    #[test]
+    fn test_get_datetime_from_headers() {
+        let email = b"From MAILER-DAEMON Tue Nov 21 12:34:56 2006\r\n\
+            Received: from b.example.com by c.example.com;\r\n \
+            \t10 Dec 2010 19:20:19 -0800\r\n\
+            Received: from a.example.com by b.example.com; Fri, 10 Dec 2010 18:00:00 -0800\r\n\
+            Resent-Date: Thu, 9 Dec 2010 10:00:00 +0000\r\n\
+            Date: Wed, 8 Dec 2010 10:00:00 +0000\r\n\
+            \r\n\
+            Date: Tue, 7 Dec 2010 10:00:00 +0000\r\n";
+        let expected = |dt: &str, source| Some((dt.to_string(), source));
+        assert_eq!(
+            get_datetime(email, ALL_SOURCES),
+            expected("2010-12-08T10:00:00+00:00", DateSource::Date)
+        );
+        assert_eq!(
+            get_datetime(email, &ALL_SOURCES[1..]),
+            expected("2010-12-10T19:20:19-08:00", DateSource::Received)
+        );
+        assert_eq!(
+            get_datetime(email, &ALL_SOURCES[2..]),
+            expected("2010-12-09T10:00:00+00:00", DateSource::ResentDate)
+        );
+        assert_eq!(
+            get_datetime(email, &ALL_SOURCES[3..]),
+            expected("2006-11-21T12:34:56+00:00", DateSource::FromLine)
+        );
+    }
+
+    #[test]
+    fn test_get_datetime_fallback() {
+        let email = b"From  : John Doe <jdoe@example.com>\n\
+            Date: Wed, 31 Dec 2010 10:00:00 +0000\n\
+            Received: from a.example.com by b.example.com\n\
+            Received: from b.example.com by c.example.com; Thu, 1 Jan 2004 00:00:00 +0000\n\
+            \n";
+        assert_eq!(get_datetime(email, &[DateSource::Date]), None);
+        assert_eq!(get_datetime(email, &[DateSource::FromLine]), None);
+        // Only the top-most Received header is used.
+        assert_eq!(get_datetime(email, ALL_SOURCES), None);
+        let email = b"Received: by a.example.com;\n Thu, 1 Jan 2004 00:00:00 +0000\n\n";
+        assert_eq!(
+            get_datetime(email, ALL_SOURCES),
+            Some((
+                "2004-01-01T00:00:00+00:00".to_string(),
+                DateSource::Received
+            ))
+        );
+    }
+
+    #[test]
+    fn test_get_datetime_from_file_name() {
+        fn get_datetime(file_name: &str) -> Option<String> {
+            get_datetime_from_file_name(Path::new(file_name)).map(|dt| dt.to_rfc3339())
+        }
+
+        assert_eq!(
+            get_datetime("new/1538824850.M951087P44546Q42Rb8cc8c7a4d62405e"),
+            Some("2018-10-06T11:20:50+00:00".to_string())
+        );
+        assert_eq!(
+            get_datetime("cur/1538824850.M951087P44546Q42R:2,S"),
+            Some("2018-10-06T11:20:50+00:00".to_string())
+        );
+        assert_eq!(get_datetime("new/0Mh4FuxGGH77dHIcPferAX"), None);
+        assert_eq!(get_datetime("new/1538824850"), None);
+        assert_eq!(get_datetime("new/.1538824850"), None);
+    }
+}
@@ -0,0 +1,110 @@
+use crate::archive::{self, ArchiveKind, ArchiveWriter};
+use crate::args::{Args, CompactArgs, ThreadArgs};
+use crate::compress::Encoder;
+use crate::execute::{get_backup_path, replace_archive, set_archive_permission};
+use crate::folder;
+use crate::index::{self, IndexEntry};
+use crate::recover;
+use crate::utils::{self, get_file_name};
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// Rewrites the archive into a single compressed stream without the given
+/// entries, with the given compression level or the default level of its
+/// format, and optionally with multiple threads. The index is written from
+/// the rewritten entries. An archive which would become empty is moved to
+/// its backup instead, along with the removal of its index.
+pub fn rewrite_archive(
+    archive_path: &Path,
+    removed: &HashSet<OsString>,
+    level: Option<u32>,
+    threading: Option<&ThreadArgs>,
+) -> Result<()> {
+    let archive_name = get_file_name(archive_path).to_string_lossy().into_owned();
+    let (_, kind) = ArchiveKind::split_suffix(&archive_name).unwrap();
+    let codec = kind.codec;
+    let (min, max) = codec.level_range();
+    let level = level.unwrap_or_else(|| codec.default_level());
+    if !(min..=max).contains(&level) {
+        bail!("compression level must be within {}..={}", min, max);
+    }
+    let mut tmp_path = archive_path.as_os_str().to_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let tmp_file = File::create(&tmp_path)?;
+    #[cfg(unix)]
+    set_archive_permission(&tmp_file)?;
+    let encoder = match threading {
+        Some(threading) => Encoder::with_threads(
+            codec,
+            level,
+            threading.threads,
+            threading.block_size.unwrap_or(0),
+            tmp_file,
+        )?,
+        None => Encoder::new(codec, level, tmp_file)?,
+    };
+    let mut writer = ArchiveWriter::new(kind.format, encoder);
+    let mut index_entries = vec![];
+    archive::read_entries(File::open(archive_path)?, |mut entry| {
+        let file_name = get_file_name(&entry.path).to_os_string();
+        if !removed.contains(&file_name) {
+            writer
+                .append(&mut entry, None)
+                .with_context(|| format!("failed to append file {:?}", file_name))?;
+            let digest = entry.digest()?;
+            index_entries.push(IndexEntry::new(&entry.path, &digest)?);
+        }
+        Ok(())
+    })?;
+    let tmp_file = writer.finish()?;
+    tmp_file.sync_all()?;
+    drop(tmp_file);
+
+    if index_entries.is_empty() {
+        fs::remove_file(&tmp_path)?;
+        fs::rename(archive_path, get_backup_path(archive_path))?;
+        index::remove_index(archive_path);
+        utils::sync_dir(archive_path.parent().unwrap())?;
+    } else {
+        replace_archive(&tmp_path, archive_path, Some(archive_path))?;
+        utils::sync_dir(archive_path.parent().unwrap())?;
+        index::write_index(archive_path, index_entries, true)?;
+    }
+    Ok(())
+}
+
+/// Rewrites the selected archives, so that streams appended by incremental
+/// packing are merged into one.
+pub fn compact(args: &Args, compact_args: &CompactArgs) -> Result<()> {
+    let _lock = recover::lock_and_recover(args)?;
+    let archives = folder::select_archives(args, &compact_args.archives)?;
+    let progress = utils::create_progress_bar(args, archives.len());
+    utils::install_packing_pool(&compact_args.threading, || {
+        archives
+            .par_iter()
+            .map(|path| {
+                let name = path.strip_prefix(&args.packed_dir).unwrap();
+                let result = rewrite_archive(
+                    path,
+                    &HashSet::new(),
+                    compact_args.level,
+                    Some(&compact_args.threading),
+                )
+                .with_context(|| format!("failed to compact {}", name.display()));
+                progress.inc(1);
+                result
+            })
+            .collect::<Result<()>>()
+    })??;
+    progress.finish_and_clear();
+    if !args.quiet {
+        eprintln!("Compacted {} archive(s)", archives.len());
+    }
+    Ok(())
+}
@@ -0,0 +1,255 @@
+use bzip2::read::MultiBzDecoder;
+use bzip2::write::BzEncoder;
+use clap::ValueEnum;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use xz2::read::XzDecoder;
+use xz2::stream::{Check, MtStreamBuilder};
+use xz2::write::XzEncoder;
+
+/// Compression format of archives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Codec {
+    Xz,
+    Zstd,
+    Gzip,
+    Bzip2,
+    /// No compression.
+    None,
+}
+
+impl Codec {
+    pub const ALL: [Codec; 5] = [
+        Codec::Xz,
+        Codec::Zstd,
+        Codec::Gzip,
+        Codec::Bzip2,
+        Codec::None,
+    ];
+
+    /// File name extension of files compressed with this codec.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Codec::Xz => ".xz",
+            Codec::Zstd => ".zst",
+            Codec::Gzip => ".gz",
+            Codec::Bzip2 => ".bz2",
+            Codec::None => "",
+        }
+    }
+
+    pub fn default_level(self) -> u32 {
+        match self {
+            Codec::Xz => 9,
+            Codec::Zstd => 3,
+            Codec::Gzip => 9,
+            Codec::Bzip2 => 9,
+            Codec::None => 0,
+        }
+    }
+
+    pub fn level_range(self) -> (u32, u32) {
+        match self {
+            Codec::Xz => (0, 9),
+            Codec::Zstd => (1, 22),
+            Codec::Gzip => (0, 9),
+            Codec::Bzip2 => (1, 9),
+            Codec::None => (0, 0),
+        }
+    }
+
+    /// Detects the format from the magic bytes at the start of the data.
+    fn detect(header: &[u8]) -> Codec {
+        if header.starts_with(b"\xfd7zXZ\x00") {
+            Codec::Xz
+        } else if header.starts_with(b"\x28\xb5\x2f\xfd") {
+            Codec::Zstd
+        } else if header.starts_with(b"\x1f\x8b") {
+            Codec::Gzip
+        } else if header.starts_with(b"BZh") {
+            Codec::Bzip2
+        } else {
+            Codec::None
+        }
+    }
+}
+
+/// Writer which compresses data with one of the codecs.
+pub enum Encoder<W: Write> {
+    Xz(XzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Gzip(GzEncoder<W>),
+    Bzip2(BzEncoder<W>),
+    None(W),
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(codec: Codec, level: u32, writer: W) -> io::Result<Self> {
+        Encoder::with_threads(codec, level, 1, 0, writer)
+    }
+
+    /// Creates an encoder which compresses with the given number of threads,
+    /// or as many threads as CPUs if it is 0. Only xz supports threads, with
+    /// which the data is split into independent blocks of the given size, or
+    /// a size chosen by liblzma if it is 0. A block size splits the data into
+    /// blocks even with a single thread.
+    pub fn with_threads(
+        codec: Codec,
+        level: u32,
+        threads: u32,
+        block_size: u64,
+        writer: W,
+    ) -> io::Result<Self> {
+        let threads = match threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get() as u32),
+            threads => threads,
+        };
+        if codec == Codec::Xz && (threads > 1 || block_size > 0) {
+            let stream = MtStreamBuilder::new()
+                .threads(threads)
+                .block_size(block_size)
+                .preset(level)
+                .check(Check::Crc64)
+                .encoder()?;
+            return Ok(Encoder::Xz(XzEncoder::new_stream(writer, stream)));
+        }
+        Ok(match codec {
+            Codec::Xz => Encoder::Xz(XzEncoder::new(writer, level)),
+            Codec::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, level as i32)?),
+            Codec::Gzip => Encoder::Gzip(GzEncoder::new(writer, flate2::Compression::new(level))),
+            Codec::Bzip2 => Encoder::Bzip2(BzEncoder::new(writer, bzip2::Compression::new(level))),
+            Codec::None => Encoder::None(writer),
+        })
+    }
+
+    /// Finishes the compressed stream and returns the underlying writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Xz(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Bzip2(encoder) => encoder.finish(),
+            Encoder::None(writer) => Ok(writer),
+        }
+    }
+
+    fn writer(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::Xz(encoder) => encoder,
+            Encoder::Zstd(encoder) => encoder,
+            Encoder::Gzip(encoder) => encoder,
+            Encoder::Bzip2(encoder) => encoder,
+            Encoder::None(writer) => writer,
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer().flush()
+    }
+}
+
+/// Opens a reader which decompresses the given archive file, with its
+/// format detected from the content rather than the file name. Archives may
+/// consist of several compressed streams, which are read one after another.
+pub fn open_archive(file: File) -> io::Result<Box<dyn Read + Send>> {
+    let mut reader = BufReader::new(file);
+    let codec = Codec::detect(reader.fill_buf()?);
+    Ok(match codec {
+        Codec::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+        Codec::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        Codec::Gzip => Box::new(MultiGzDecoder::new(reader)),
+        Codec::Bzip2 => Box::new(MultiBzDecoder::new(reader)),
+        Codec::None => Box::new(reader),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_round_trip() {
+        let data = b"From: a@example.com\r\n\r\nHello, world!\r\n".repeat(100);
+        for codec in Codec::ALL {
+            let mut encoder = Encoder::new(codec, codec.default_level(), vec![]).unwrap();
+            encoder.write_all(&data).unwrap();
+            let compressed = encoder.finish().unwrap();
+            assert_eq!(Codec::detect(&compressed), codec);
+            let mut file = tempfile::tempfile().unwrap();
+            io::copy(&mut Cursor::new(compressed), &mut file).unwrap();
+            io::Seek::rewind(&mut file).unwrap();
+            let mut decompressed = vec![];
+            open_archive(file)
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, data, "{:?}", codec);
+        }
+    }
+
+    /// Counts blocks of a single xz stream from the number of records in
+    /// its index, which precedes the 12-byte stream footer.
+    fn count_xz_blocks(compressed: &[u8]) -> u64 {
+        let footer = &compressed[compressed.len() - 12..];
+        let backward_size = u32::from_le_bytes([footer[4], footer[5], footer[6], footer[7]]);
+        let index_start = compressed.len() - 12 - (backward_size as usize + 1) * 4;
+        let mut count = 0;
+        for (i, &b) in compressed[index_start + 1..].iter().enumerate() {
+            count |= u64::from(b & 0x7f) << (7 * i);
+            if b & 0x80 == 0 {
+                break;
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn test_multithreaded_xz() {
+        let data = b"From: a@example.com\r\n\r\nHello, world!\r\n".repeat(10000);
+        // A block size alone splits the data into blocks as well.
+        for threads in [4, 1] {
+            let mut encoder =
+                Encoder::with_threads(Codec::Xz, 6, threads, 64 * 1024, vec![]).unwrap();
+            encoder.write_all(&data).unwrap();
+            let compressed = encoder.finish().unwrap();
+            assert_eq!(count_xz_blocks(&compressed), 6, "{} thread(s)", threads);
+            let mut decompressed = vec![];
+            XzDecoder::new(&compressed[..])
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(decompressed, data);
+        }
+    }
+
+    #[test]
+    fn test_concatenated_streams() {
+        for codec in Codec::ALL {
+            let mut file = tempfile::tempfile().unwrap();
+            for data in [&b"first stream\n"[..], b"second stream\n"] {
+                let mut encoder = Encoder::new(codec, codec.default_level(), &file).unwrap();
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap();
+            }
+            io::Seek::rewind(&mut file).unwrap();
+            let mut decompressed = vec![];
+            open_archive(file)
+                .unwrap()
+                .read_to_end(&mut decompressed)
+                .unwrap();
+            assert_eq!(
+                decompressed, b"first stream\nsecond stream\n",
+                "{:?}",
+                codec
+            );
+        }
+    }
+}
@@ -1,7 +1,8 @@
 mod parser;
 
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, NaiveDateTime};
 use combine::Parser;
+use std::str;
 
 /// Parses a data and time string used in Internet Message Format based on what
 /// specified in RFC 5322 section 3.3.
@@ -24,6 +25,32 @@ pub fn parse_datetime(s: &[u8]) -> Option<DateTime<FixedOffset>> {
     }
 }
 
+/// Parses the data and time in the `From ` line of mbox format, which is in
+/// the format of `asctime(3)`, e.g. `Tue Nov 21 12:34:56 2006`, optionally
+/// followed by a numeric time zone. UTC is assumed when no time zone is given.
+///
+/// The given string is the part after `From `, i.e. the envelope sender
+/// followed by the date and time.
+pub fn parse_mbox_datetime(s: &[u8]) -> Option<DateTime<FixedOffset>> {
+    let s = str::from_utf8(s).ok()?;
+    let mut tokens: Vec<_> = s.split_whitespace().collect();
+    let mut offset = FixedOffset::east_opt(0).unwrap();
+    if let Some(zone) = tokens.last().filter(|t| t.starts_with(['+', '-'])) {
+        offset = DateTime::parse_from_str(&format!("1970-01-01 0:0 {}", zone), "%F %R %z")
+            .ok()?
+            .timezone();
+        tokens.pop();
+    }
+    // Date and time takes the last five tokens, and there should be at least
+    // one token for the sender before them.
+    if tokens.len() < 6 {
+        return None;
+    }
+    let dt = tokens[tokens.len() - 5..].join(" ");
+    let dt = NaiveDateTime::parse_from_str(&dt, "%a %b %d %H:%M:%S %Y").ok()?;
+    Some(DateTime::from_naive_utc_and_offset(dt - offset, offset))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -82,4 +109,39 @@ mod tests {
             assert_eq!(parse_datetime(s), None);
         }
     }
+
+    #[test]
+    fn test_mbox_parsed() {
+        let utc = FixedOffset::east_opt(0).unwrap();
+        let cet = FixedOffset::east_opt(3600).unwrap();
+        let testcases: Vec<(&[u8], _)> = vec![
+            (
+                b"MAILER-DAEMON Tue Nov 21 12:34:56 2006",
+                utc.with_ymd_and_hms(2006, 11, 21, 12, 34, 56).unwrap(),
+            ),
+            (
+                b"jdoe@example.com  Fri Nov  9 01:10:02 2007\r",
+                utc.with_ymd_and_hms(2007, 11, 9, 1, 10, 2).unwrap(),
+            ),
+            (
+                b"jdoe@example.com Fri Nov 9 01:10:02 2007 +0100",
+                cet.with_ymd_and_hms(2007, 11, 9, 1, 10, 2).unwrap(),
+            ),
+        ];
+        for (s, dt) in testcases {
+            assert_eq!(parse_mbox_datetime(s), Some(dt));
+        }
+    }
+
+    #[test]
+    fn test_mbox_not_parsed() {
+        let testcases: &[&[u8]] = &[
+            b"Tue Nov 21 12:34:56 2006",
+            b"jdoe@example.com Wed Nov 21 12:34:56 2006",
+            b" : John Doe <jdoe@example.com>",
+        ];
+        for s in testcases {
+            assert_eq!(parse_mbox_datetime(s), None);
+        }
+    }
 }
@@ -1,49 +1,140 @@
+use crate::archive::{self, ArchiveFormat, ArchiveWriter, Entry};
 use crate::args::Args;
-use crate::utils;
-use crate::verify::{HashResult, StreamHasher};
-use anyhow::{Context, Result};
+use crate::collect::Email;
+use crate::compress::Encoder;
+use crate::folder::Folder;
+use crate::index::{self, IndexEntry};
+use crate::utils::{self, get_file_name};
+use crate::verify::HashResult;
+use anyhow::{bail, Context, Result};
 use rayon::prelude::*;
 use std::collections::HashMap;
 use std::ffi::{OsStr, OsString};
-use std::fs::{self, File};
-use std::io::{Read, Write};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
 #[cfg(unix)]
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
-use tar::{self, Archive as TarArchive, Builder as TarBuilder};
-use xz2::read::XzDecoder;
-use xz2::write::XzEncoder;
 
-fn get_file_name(path: &Path) -> &OsStr {
-    path.file_name().expect("Unexpected path")
+/// Returns the unique part of a maildir file name, i.e. without the info
+/// suffix (`:2,FLAGS`) which changes when an email is moved to maildir/cur
+/// or when its flags are updated.
+/// https://cr.yp.to/proto/maildir.html
+pub fn get_unique_name(file_name: &OsStr) -> &OsStr {
+    match file_name.to_str() {
+        Some(name) => match name.find(':') {
+            Some(pos) => OsStr::new(&name[..pos]),
+            None => file_name,
+        },
+        None => file_name,
+    }
+}
+
+/// Reads the email as an entry of archives in the given format. Emails are
+/// stored under their unique name in tar archives, so that the same email in
+/// maildir/new and maildir/cur, or with different flags, is recognized as the
+/// same entry.
+pub fn read_email(format: ArchiveFormat, email: &Email) -> Result<Entry<'static>> {
+    let file_name = get_unique_name(get_file_name(&email.path));
+    let file =
+        File::open(&email.path).with_context(|| format!("failed to open {:?}", file_name))?;
+    Entry::from_reader(PathBuf::from(file_name), file.metadata()?.len(), file)
+        .into_format(format)
+        .with_context(|| format!("failed to read {:?}", file_name))
+}
+
+/// Returns the name of the email as an entry of archives in the given format,
+/// which only requires reading the email for mbox archives.
+fn get_entry_name(format: ArchiveFormat, email: &Email) -> Result<OsString> {
+    match format {
+        ArchiveFormat::Tar => Ok(get_unique_name(get_file_name(&email.path)).to_os_string()),
+        ArchiveFormat::Mbox => Ok(read_email(format, email)?.path.into_os_string()),
+    }
 }
 
 fn fill_archive_from(
     src: File,
-    builder: &mut TarBuilder<impl Write>,
+    writer: &mut ArchiveWriter<impl Write>,
+    format: ArchiveFormat,
     files: &mut HashMap<OsString, HashResult>,
+    index_entries: &mut Vec<IndexEntry>,
 ) -> Result<()> {
-    let xz_reader = XzDecoder::new(src);
-    let mut tar_archive = TarArchive::new(xz_reader);
-    for entry in tar_archive.entries()? {
-        let entry = entry?;
-        // We have to clone the header, otherwise we cannot feed entry
-        // to builder.append(). See alexcrichton/tar-rs#122.
-        let header = entry.header().clone();
-        let file_name = get_file_name(&header.path()?).to_os_string();
-        let mut hasher = StreamHasher::new(entry);
-        builder
-            .append(&header, &mut hasher)
+    archive::read_entries(src, |entry| {
+        // Entries are converted when the existing archive is in the other
+        // format.
+        let mut entry = entry.into_format(format)?;
+        let file_name = get_file_name(&entry.path).to_os_string();
+        if files.contains_key(&file_name) {
+            return Ok(());
+        }
+        writer
+            .append(&mut entry, None)
             .with_context(|| format!("failed to append file {:?}", file_name))?;
         // Add the path to the files map.
-        files.insert(file_name, hasher.get_result());
+        let digest = entry.digest()?;
+        let index_entry = IndexEntry::new(&entry.path, &digest)?;
+        files.insert(file_name, index_entry.sha512);
+        index_entries.push(index_entry);
+        Ok(())
+    })
+}
+
+/// Reads hashes of all entries in the given archive.
+pub fn read_archive_hashes(src: File) -> Result<HashMap<OsString, HashResult>> {
+    let mut files = HashMap::new();
+    read_archive_hashes_into(src, &mut files)?;
+    Ok(files)
+}
+
+/// Reads hashes of entries in the given archive into the map. Entries before
+/// any error are kept in the map, so that a broken archive can be read as
+/// far as possible.
+pub fn read_archive_hashes_into(
+    src: File,
+    files: &mut HashMap<OsString, HashResult>,
+) -> Result<()> {
+    archive::read_entries(src, |mut entry| {
+        let file_name = get_file_name(&entry.path).to_os_string();
+        files.insert(file_name, entry.digest()?.hash);
+        Ok(())
+    })
+}
+
+/// Finds the existing archive of the given name. It may be in a different
+/// format, in which case it gets converted to the current one when packing.
+/// If there are several, the one in the current format is preferred, or else
+/// the first in a fixed order.
+pub fn find_existing_archive(args: &Args, folder: &Folder, name: &str) -> Option<PathBuf> {
+    let mut existing = args
+        .archive_kind()
+        .suffixes()
+        .map(|suffix| folder.packed_dir.join(format!("{}{}", name, suffix)))
+        .filter(|path| path.is_file());
+    let result = existing.next()?;
+    // Merging duplicates isn't safe without knowing which one is newer, so
+    // they are left for the user to sort out.
+    for other in existing {
+        eprintln!(
+            "Warning: ignoring {}, which has the same name as {}",
+            other.display(),
+            result.display()
+        );
     }
+    Some(result)
+}
 
-    Ok(())
+fn create_encoder<W: Write>(args: &Args, writer: W) -> io::Result<Encoder<W>> {
+    Encoder::with_threads(
+        args.compression,
+        args.compression_level,
+        args.threading.threads,
+        args.threading.block_size.unwrap_or(0),
+        writer,
+    )
 }
 
 #[cfg(unix)]
-fn set_archive_permission(file: &File) -> Result<()> {
+pub fn set_archive_permission(file: &File) -> Result<()> {
     let mut perms = file.metadata()?.permissions();
     let mode = (perms.mode() & !0o777) | 0o600;
     perms.set_mode(mode);
@@ -51,81 +142,510 @@ fn set_archive_permission(file: &File) -> Result<()> {
     Ok(())
 }
 
-fn do_archive(args: &Args, name: &str, emails: Vec<PathBuf>) -> Result<()> {
-    let archive_name = format!("{}.tar.xz", name);
-    let archive_path = args.packed_dir.join(&archive_name);
+/// Statistics of packing emails into an archive.
+#[derive(Default)]
+struct ArchiveStats {
+    /// Number of emails added to the archive.
+    added: usize,
+    /// Number of emails in the archive already with identical content.
+    present: usize,
+    /// Emails left in the maildir, because the archive contains a different
+    /// email of the same name.
+    conflicting: Vec<PathBuf>,
+    /// Emails packed but failed to be removed from the maildir.
+    not_removed: Vec<(PathBuf, io::Error)>,
+}
+
+/// Outcome of packing emails into an archive.
+pub struct ArchiveOutcome {
+    name: String,
+    result: Result<ArchiveStats>,
+}
+
+/// Kinds of failure, from the least severe to the most severe. The exit code
+/// of the process is decided by the most severe failure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Failure {
+    /// Some emails were left in the maildir, either skipped or failed to be
+    /// removed after packing.
+    EmailsLeft,
+    /// Some archives failed to be written.
+    ArchiveFailed,
+}
+
+impl Failure {
+    pub fn exit_code(self) -> u8 {
+        match self {
+            Failure::EmailsLeft => 3,
+            Failure::ArchiveFailed => 4,
+        }
+    }
+}
+
+fn do_archive(
+    args: &Args,
+    folder: &Folder,
+    name: &str,
+    emails: Vec<Email>,
+) -> Result<ArchiveStats> {
+    let archive_name = format!("{}{}", name, args.archive_kind().suffix());
+    let tmp_path = folder.packed_dir.join(format!("{}.tmp", &archive_name));
+    let result = write_archive(args, folder, name, &archive_name, &tmp_path, &emails);
+    if result.is_err() {
+        // It's okay if it fails, since the temporary file may not have been
+        // created yet.
+        let _ = fs::remove_file(&tmp_path);
+    }
+    let (mut stats, packed) = result?;
+
+    // Remove the archived emails unless we are asked to keep them. Copies
+    // in the packed directory, i.e. emails unpacked for re-bucketing and
+    // imported messages, are always removed.
+    stats.not_removed = packed
+        .into_par_iter()
+        .filter(|email| !args.keep || email.starts_with(&folder.packed_dir))
+        .filter_map(|email| match fs::remove_file(&email) {
+            Ok(()) => None,
+            Err(e) => Some((email, e)),
+        })
+        .collect();
+    // Explicitly drop to silence clippy.
+    drop(emails);
+
+    Ok(stats)
+}
+
+/// Writes the archive, and returns the statistics along with the emails
+/// which are in the archive now.
+fn write_archive(
+    args: &Args,
+    folder: &Folder,
+    name: &str,
+    archive_name: &str,
+    tmp_path: &Path,
+    emails: &[Email],
+) -> Result<(ArchiveStats, Vec<PathBuf>)> {
+    let archive_path = folder.packed_dir.join(archive_name);
+    let existing_path = find_existing_archive(args, folder, name);
 
-    let tmp_path = args.packed_dir.join(format!("{}.tmp", &archive_name));
-    let tmp_file = File::create(&tmp_path)?;
+    // If the index tells that every email is in the archive already, there
+    // is no need to decompress the archive, unless --verify asks to check
+    // the emails against the archive itself. When appending, the archive is
+    // only decompressed if it has no valid index.
+    if existing_path.as_ref() == Some(&archive_path) {
+        let entries = if args.append {
+            let entries = index::read_or_build_index(&archive_path)
+                .context("failed to read the existing archive")?;
+            Some(entries)
+        } else {
+            index::read_index(&archive_path)
+        };
+        if let Some(entries) = entries {
+            let files: HashMap<_, _> = entries
+                .iter()
+                .map(|entry| (OsString::from(&entry.name), entry.sha512))
+                .collect();
+            if all_archived(args.format, emails, &files)? {
+                if !args.verify {
+                    return check_archived_emails(args.format, emails, &files);
+                }
+                let files = read_archive_hashes(File::open(&archive_path)?)
+                    .context("failed to read the existing archive")?;
+                if all_archived(args.format, emails, &files)? {
+                    return check_archived_emails(args.format, emails, &files);
+                }
+                // The index doesn't match the archive, so drop it, and
+                // rewrite the archive below.
+                index::remove_index(&archive_path);
+            } else if args.append && !is_linked(&archive_path)? {
+                return append_archive(args, &archive_path, emails, files, entries);
+            }
+        }
+    }
+
+    // The name may contain directories.
+    fs::create_dir_all(archive_path.parent().unwrap())?;
+    let tmp_file = File::create(tmp_path)
+        .with_context(|| format!("failed to create {:?}", get_file_name(tmp_path)))?;
     #[cfg(unix)]
     set_archive_permission(&tmp_file)?;
 
-    let xz_writer = XzEncoder::new(tmp_file, 9);
-    let mut tar_builder = TarBuilder::new(xz_writer);
-    tar_builder.mode(tar::HeaderMode::Deterministic);
+    let encoder = create_encoder(args, tmp_file)?;
+    let mut writer = ArchiveWriter::new(args.format, encoder);
 
-    // Fill files from existing archive and backup it.
+    // Fill files from existing archive.
     let mut existing_files = HashMap::new();
-    if let Ok(file) = File::open(&archive_path) {
-        fill_archive_from(file, &mut tar_builder, &mut existing_files)?;
-        let backup_path = args.packed_dir.join(format!("{}.bak", archive_name));
-        // Remove old backup file. It's okay if it fails, because it's being overridden anyway.
-        let _ = fs::remove_file(&backup_path);
-        fs::hard_link(&archive_path, &backup_path).context("failed to link backup file")?;
+    let mut index_entries = Vec::new();
+    if let Some(existing_path) = &existing_path {
+        let file = File::open(existing_path)?;
+        fill_archive_from(
+            file,
+            &mut writer,
+            args.format,
+            &mut existing_files,
+            &mut index_entries,
+        )
+        .context("failed to read the existing archive")?;
     }
 
-    // Adding emails to the archive.
-    let existing_files = existing_files;
-    for email in &emails {
-        let file_name = get_file_name(email);
-        let mut file =
-            File::open(email).with_context(|| format!("failed to open {:?}", file_name))?;
-        if let Some(expected_hash) = existing_files.get(file_name) {
-            // The file exists, let's check whether the hash matches.
-            let mut hasher = StreamHasher::new(file);
-            let mut buf = [0; 4096];
-            while let Ok(size) = hasher.read(&mut buf) {
-                if size == 0 {
-                    break;
-                }
+    let (stats, packed) = add_emails(
+        emails,
+        &mut writer,
+        args.format,
+        &mut existing_files,
+        &mut index_entries,
+    )?;
+
+    // Close the archive, and make sure it is on disk before it replaces the
+    // existing one.
+    let tmp_file = writer.finish()?;
+    if !args.no_sync {
+        tmp_file.sync_all()?;
+    }
+    drop(tmp_file);
+    if stats.added == 0 && existing_path.as_ref() == Some(&archive_path) {
+        // Nothing is added, so just keep the existing archive untouched.
+        fs::remove_file(tmp_path)?;
+        if index::read_index(&archive_path).is_none() {
+            write_archive_index(args, &archive_path, index_entries);
+        }
+    } else {
+        if args.verify {
+            verify_archive(tmp_path, &existing_files).context("failed to verify the archive")?;
+        }
+        replace_archive(tmp_path, &archive_path, existing_path.as_deref())?;
+        if !args.no_sync {
+            // Directories may have been created for the archive as well.
+            let mut dir = archive_path.parent();
+            while let Some(d) = dir.filter(|d| d.starts_with(&folder.packed_dir)) {
+                utils::sync_dir(d)?;
+                dir = d.parent();
             }
-            let hash = hasher.get_result();
-            if expected_hash[..] != hash[..] {
-                eprintln!(
-                    "Warning: {:?} exists in the archive \
-                     but has different content",
-                    file_name
-                );
+        }
+        write_archive_index(args, &archive_path, index_entries);
+    }
+
+    Ok((stats, packed))
+}
+
+/// Adds the emails into the archive unless they are in the given files
+/// already. Returns the statistics along with the emails which are in the
+/// archive now.
+fn add_emails(
+    emails: &[Email],
+    writer: &mut ArchiveWriter<impl Write>,
+    format: ArchiveFormat,
+    existing_files: &mut HashMap<OsString, HashResult>,
+    index_entries: &mut Vec<IndexEntry>,
+) -> Result<(ArchiveStats, Vec<PathBuf>)> {
+    let mut stats = ArchiveStats::default();
+    let mut packed = Vec::with_capacity(emails.len());
+    for email in emails {
+        let mut entry = read_email(format, email)?;
+        let file_name = get_file_name(&entry.path).to_os_string();
+        if let Some(expected_hash) = existing_files.get(&file_name) {
+            // The file exists, let's check whether the hash matches.
+            if expected_hash[..] != entry.digest()?.hash[..] {
+                stats.conflicting.push(email.path.clone());
+                continue;
             }
+            stats.present += 1;
         } else {
-            tar_builder
-                .append_file(file_name, &mut file)
+            let mut header = tar::Header::new_gnu();
+            header
+                .set_metadata_in_mode(&fs::metadata(&email.path)?, tar::HeaderMode::Deterministic);
+            entry.header = Some(header);
+            writer
+                .append(&mut entry, email.datetime.as_ref())
                 .with_context(|| format!("failed to append file {:?}", file_name))?;
+            let digest = entry.digest()?;
+            let index_entry = IndexEntry::new(&entry.path, &digest)?;
+            existing_files.insert(file_name, index_entry.sha512);
+            index_entries.push(index_entry);
+            stats.added += 1;
         }
+        packed.push(email.path.clone());
     }
+    Ok((stats, packed))
+}
 
-    // Close the archive and move it to the destination.
-    drop(tar_builder.into_inner()?.finish()?);
-    fs::rename(&tmp_path, &archive_path)?;
+/// Whether the archive file has other links, e.g. a backup restored by the
+/// recovery, in which case it must not be modified in place.
+#[cfg(unix)]
+fn is_linked(path: &Path) -> io::Result<bool> {
+    Ok(fs::metadata(path)?.nlink() > 1)
+}
 
-    // Remove the archived emails.
-    emails
-        .par_iter()
-        .for_each(|email| fs::remove_file(email).unwrap());
-    // Explicitly drop to silence clippy.
-    drop(emails);
+#[cfg(not(unix))]
+fn is_linked(_path: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Returns the path of the journal of appending to the given archive.
+pub fn get_journal_path(archive_path: &Path) -> PathBuf {
+    let mut journal_path = archive_path.as_os_str().to_os_string();
+    journal_path.push(".append");
+    PathBuf::from(journal_path)
+}
+
+/// Appends the new emails to the existing archive as a new compressed
+/// stream, so that the existing emails are not recompressed. The original
+/// size of the archive is recorded in a journal while appending, so that
+/// the archive can be truncated back if the run is interrupted.
+fn append_archive(
+    args: &Args,
+    archive_path: &Path,
+    emails: &[Email],
+    mut existing_files: HashMap<OsString, HashResult>,
+    mut index_entries: Vec<IndexEntry>,
+) -> Result<(ArchiveStats, Vec<PathBuf>)> {
+    let dir = archive_path.parent().unwrap();
+    let archive_file = OpenOptions::new().append(true).open(archive_path)?;
+    let original_len = archive_file.metadata()?.len();
+    let journal_path = get_journal_path(archive_path);
+    let mut journal = File::create(&journal_path).context("failed to create the journal")?;
+    write!(journal, "{}", original_len)?;
+    if !args.no_sync {
+        journal.sync_all()?;
+        utils::sync_dir(dir)?;
+    }
+    drop(journal);
 
+    let result = append_emails(
+        args,
+        archive_path,
+        &archive_file,
+        emails,
+        &mut existing_files,
+        &mut index_entries,
+    );
+    if result.is_err() {
+        // Roll back to the original archive.
+        archive_file.set_len(original_len)?;
+        if !args.no_sync {
+            archive_file.sync_all()?;
+        }
+    }
+    fs::remove_file(&journal_path)?;
+    if !args.no_sync {
+        utils::sync_dir(dir)?;
+    }
+    let result = result?;
+    write_archive_index(args, archive_path, index_entries);
+    Ok(result)
+}
+
+/// Writes a new compressed stream with the new emails at the end of the
+/// archive file.
+fn append_emails(
+    args: &Args,
+    archive_path: &Path,
+    archive_file: &File,
+    emails: &[Email],
+    existing_files: &mut HashMap<OsString, HashResult>,
+    index_entries: &mut Vec<IndexEntry>,
+) -> Result<(ArchiveStats, Vec<PathBuf>)> {
+    let encoder = create_encoder(args, archive_file)?;
+    let mut writer = ArchiveWriter::new(args.format, encoder);
+    let result = add_emails(
+        emails,
+        &mut writer,
+        args.format,
+        existing_files,
+        index_entries,
+    )?;
+    writer.finish()?;
+    if !args.no_sync {
+        archive_file.sync_all()?;
+    }
+    if args.verify {
+        verify_archive(archive_path, existing_files).context("failed to verify the archive")?;
+    }
+    Ok(result)
+}
+
+/// Whether the names of all emails are in the given files of an archive.
+fn all_archived(
+    format: ArchiveFormat,
+    emails: &[Email],
+    files: &HashMap<OsString, HashResult>,
+) -> Result<bool> {
+    for email in emails {
+        if !files.contains_key(&get_entry_name(format, email)?) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Checks emails against the files in the archive, when all of them are
+/// known to be there.
+fn check_archived_emails(
+    format: ArchiveFormat,
+    emails: &[Email],
+    files: &HashMap<OsString, HashResult>,
+) -> Result<(ArchiveStats, Vec<PathBuf>)> {
+    let mut stats = ArchiveStats::default();
+    let mut packed = Vec::with_capacity(emails.len());
+    for email in emails {
+        let mut entry = read_email(format, email)?;
+        if files[get_file_name(&entry.path)][..] != entry.digest()?.hash[..] {
+            stats.conflicting.push(email.path.clone());
+            continue;
+        }
+        stats.present += 1;
+        packed.push(email.path.clone());
+    }
+    Ok((stats, packed))
+}
+
+/// Writes the index of the archive. A failure is only a warning, since the
+/// archive itself is fine and the index can be rebuilt later.
+fn write_archive_index(args: &Args, archive_path: &Path, entries: Vec<IndexEntry>) {
+    if let Err(e) = index::write_index(archive_path, entries, !args.no_sync) {
+        eprintln!(
+            "Warning: failed to write the index of {}: {:#}",
+            get_file_name(archive_path).to_string_lossy(),
+            e
+        );
+    }
+}
+
+/// Reads the finished archive back, and checks that it contains exactly the
+/// given files, i.e. entries of the existing archive and the new emails, with
+/// the same content.
+fn verify_archive(path: &Path, expected: &HashMap<OsString, HashResult>) -> Result<()> {
+    let files = read_archive_hashes(File::open(path)?)?;
+    for (file_name, expected_hash) in expected {
+        match files.get(file_name) {
+            Some(hash) if hash[..] == expected_hash[..] => {}
+            Some(_) => bail!("{:?} has different content", file_name),
+            None => bail!("{:?} is missing", file_name),
+        }
+    }
+    if files.len() != expected.len() {
+        bail!("archive has unexpected entries");
+    }
+    Ok(())
+}
+
+/// Returns the path of the backup of the given archive.
+pub fn get_backup_path(archive_path: &Path) -> PathBuf {
+    let mut backup_path = archive_path.as_os_str().to_os_string();
+    backup_path.push(".bak");
+    PathBuf::from(backup_path)
+}
+
+/// Backups the existing archive, and moves the finished temporary archive to
+/// the destination. The existing archive is removed if it is in a different
+/// format, i.e. at a different path.
+pub fn replace_archive(
+    tmp_path: &Path,
+    archive_path: &Path,
+    existing_path: Option<&Path>,
+) -> Result<()> {
+    if let Some(existing_path) = existing_path {
+        let backup_path = get_backup_path(existing_path);
+        // Remove old backup file. It's okay if it fails, because it's being overridden anyway.
+        let _ = fs::remove_file(&backup_path);
+        fs::hard_link(existing_path, &backup_path).context("failed to link backup file")?;
+    }
+    fs::rename(tmp_path, archive_path)?;
+    if let Some(existing_path) = existing_path.filter(|&path| path != archive_path) {
+        fs::remove_file(existing_path)?;
+        index::remove_index(existing_path);
+    }
     Ok(())
 }
 
-pub fn archive_emails(args: &Args, map: HashMap<String, Vec<PathBuf>>) {
+pub fn archive_emails(
+    args: &Args,
+    folder: &Folder,
+    map: HashMap<String, Vec<Email>>,
+) -> Result<Vec<ArchiveOutcome>> {
     let progress = utils::create_progress_bar(args, map.len());
     progress.tick();
-    map.into_par_iter().for_each(|(name, emails)| {
-        do_archive(args, &name, emails)
-            .with_context(|| format!("failed to archive {}", name))
-            .unwrap();
-        progress.inc(1);
-    });
+    let mut outcomes: Vec<_> = utils::install_packing_pool(&args.threading, || {
+        map.into_par_iter()
+            .map(|(name, emails)| {
+                let result = do_archive(args, folder, &name, emails);
+                progress.inc(1);
+                ArchiveOutcome { name, result }
+            })
+            .collect()
+    })?;
     progress.finish_and_clear();
+    outcomes.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+    // Make the removal of emails durable after the archives are. Copies in
+    // the packed directory are removed even with --keep.
+    if !args.no_sync {
+        let mut dirs = vec![folder.staging_dir(), folder.import_dir()];
+        if !args.keep {
+            dirs.extend([folder.path.join("new"), folder.path.join("cur")]);
+        }
+        for dir in dirs.iter().filter(|dir| dir.is_dir()) {
+            utils::sync_dir(dir).with_context(|| format!("failed to sync {}", dir.display()))?;
+        }
+    }
+    Ok(outcomes)
+}
+
+/// Reports the outcome of each archive and a summary of them, and returns
+/// the most severe failure if any. Failures and emails left in the maildir
+/// are always reported, while successful archives are only listed if
+/// verbose is set.
+pub fn report_outcomes(args: &Args, outcomes: &[ArchiveOutcome]) -> Option<Failure> {
+    let mut failure = None;
+    let (mut added, mut present, mut skipped, mut failed) = (0, 0, 0, 0);
+    for outcome in outcomes {
+        let stats = match &outcome.result {
+            Ok(stats) => stats,
+            Err(e) => {
+                eprintln!("Error: failed to archive {}: {:#}", outcome.name, e);
+                failure = Some(Failure::ArchiveFailed);
+                failed += 1;
+                continue;
+            }
+        };
+        for email in &stats.conflicting {
+            eprintln!(
+                "Warning: {:?} exists in archive {} but has different content, skipped",
+                get_file_name(email),
+                outcome.name
+            );
+        }
+        for (email, e) in &stats.not_removed {
+            eprintln!("Warning: failed to remove {:?}: {}", email, e);
+        }
+        if !stats.conflicting.is_empty() || !stats.not_removed.is_empty() {
+            failure = failure.max(Some(Failure::EmailsLeft));
+        }
+        if args.verbose {
+            eprintln!(
+                "{}: {} email(s) added, {} already archived",
+                outcome.name, stats.added, stats.present
+            );
+        }
+        added += stats.added;
+        present += stats.present;
+        skipped += stats.conflicting.len();
+    }
+
+    if !args.quiet && !outcomes.is_empty() {
+        let mut summary = format!(
+            "Packed {} archive(s): {} email(s) added, {} already archived",
+            outcomes.len() - failed,
+            added,
+            present
+        );
+        if skipped > 0 {
+            summary += &format!(", {} skipped", skipped);
+        }
+        if failed > 0 {
+            summary += &format!("; {} archive(s) failed", failed);
+        }
+        eprintln!("{}", summary);
+    }
+    failure
 }
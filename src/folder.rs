@@ -0,0 +1,159 @@
+use crate::archive::ArchiveKind;
+use crate::args::Args;
+use crate::utils::get_file_name;
+use anyhow::{bail, Result};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// A maildir folder to be packed.
+pub struct Folder {
+    /// Name of the folder without the leading dot, e.g. `Lists.rust`, or
+    /// empty for the root folder.
+    pub name: String,
+    /// Path to the maildir of this folder.
+    pub path: PathBuf,
+    /// The directory we put packed archives of this folder in.
+    pub packed_dir: PathBuf,
+    /// Packed directories of subfolders which are nested inside packed_dir,
+    /// and should be skipped when walking it.
+    pub nested_packed_dirs: Vec<PathBuf>,
+}
+
+impl Folder {
+    /// The directory where emails unpacked from existing archives are staged
+    /// before being packed again. See `rebucket::unpack_archives`.
+    pub fn staging_dir(&self) -> PathBuf {
+        self.packed_dir.join(".staging")
+    }
+
+    /// The directory where messages from mbox files and MH folders are
+    /// written before being packed. See `collect::import_mbox_files` and
+    /// `collect::import_mh_folders`.
+    pub fn import_dir(&self) -> PathBuf {
+        self.packed_dir.join(".import")
+    }
+
+    /// Lists all files in the packed directory recursively, since archive
+    /// names may contain directories. Hidden directories and packed
+    /// directories of subfolders are skipped.
+    pub fn list_packed_files(&self) -> io::Result<Vec<PathBuf>> {
+        fn walk(folder: &Folder, dir: &Path, result: &mut Vec<PathBuf>) -> io::Result<()> {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let path = entry.path();
+                if !entry.file_type()?.is_dir() {
+                    result.push(path);
+                } else if !get_file_name(&path).to_string_lossy().starts_with('.')
+                    && !folder.nested_packed_dirs.contains(&path)
+                {
+                    walk(folder, &path, result)?;
+                }
+            }
+            Ok(())
+        }
+
+        let mut result = vec![];
+        if self.packed_dir.is_dir() {
+            walk(self, &self.packed_dir, &mut result)?;
+        }
+        result.sort();
+        Ok(result)
+    }
+}
+
+/// Lists folders to be packed. It always includes the root maildir, and if
+/// recursive mode is enabled, also every Maildir++ subfolder of it.
+/// https://www.courier-mta.org/imap/README.maildirquota.html
+pub fn list_folders(args: &Args) -> Result<Vec<Folder>> {
+    list_folders_with(args, args.recursive)
+}
+
+/// Lists the root maildir and every Maildir++ subfolder of it, whether
+/// recursive mode is enabled or not, since the packed directory holds the
+/// archives of all of them.
+pub fn list_all_folders(args: &Args) -> Result<Vec<Folder>> {
+    list_folders_with(args, true)
+}
+
+fn list_folders_with(args: &Args, recursive: bool) -> Result<Vec<Folder>> {
+    let mut folders = vec![Folder {
+        name: String::new(),
+        path: args.maildir.clone(),
+        packed_dir: args.packed_dir.clone(),
+        nested_packed_dirs: vec![],
+    }];
+    if !recursive {
+        return Ok(folders);
+    }
+
+    let mut subfolders = vec![];
+    for entry in fs::read_dir(&args.maildir)? {
+        let path = entry?.path();
+        let file_name = get_file_name(&path);
+        let name = match file_name.to_str() {
+            Some(name) => name,
+            None => {
+                eprintln!("Warning: skipping folder {:?} with invalid name", file_name);
+                continue;
+            }
+        };
+        // Maildir++ subfolders are directories whose name starts with a dot,
+        // and which have the maildir structure.
+        let name = match name.strip_prefix('.') {
+            Some(name) if !name.is_empty() && name != "." => name,
+            _ => continue,
+        };
+        if !path.join("new").is_dir() && !path.join("cur").is_dir() {
+            continue;
+        }
+        subfolders.push(Folder {
+            name: name.to_string(),
+            packed_dir: args.packed_dir.join(name),
+            nested_packed_dirs: vec![],
+            path,
+        });
+    }
+    subfolders.sort_by(|a, b| a.name.cmp(&b.name));
+    folders[0].nested_packed_dirs = subfolders.iter().map(|f| f.packed_dir.clone()).collect();
+    folders.extend(subfolders);
+    Ok(folders)
+}
+
+/// Lists all archives in the packed directory, including those of
+/// subfolders.
+pub fn list_all_archives(args: &Args) -> io::Result<Vec<PathBuf>> {
+    let folder = Folder {
+        name: String::new(),
+        path: args.maildir.clone(),
+        packed_dir: args.packed_dir.clone(),
+        nested_packed_dirs: vec![],
+    };
+    let mut archives = folder.list_packed_files()?;
+    archives
+        .retain(|path| ArchiveKind::strip_suffix(&get_file_name(path).to_string_lossy()).is_some());
+    Ok(archives)
+}
+
+/// Selects archives by the given names, which are paths relative to the
+/// packed directory with or without the suffix.
+pub fn select_archives(args: &Args, names: &[String]) -> Result<Vec<PathBuf>> {
+    let archives = list_all_archives(args)?;
+    if names.is_empty() {
+        return Ok(archives);
+    }
+    let mut result = vec![];
+    for name in names {
+        let found = archives.iter().find(|path| {
+            let relative_path = path.strip_prefix(&args.packed_dir).unwrap();
+            let relative_path = relative_path.to_string_lossy();
+            relative_path == name.as_str()
+                || ArchiveKind::strip_suffix(&relative_path) == Some(name)
+        });
+        match found {
+            Some(path) => result.push(path.clone()),
+            None => bail!("archive {} not found", name),
+        }
+    }
+    Ok(result)
+}
@@ -0,0 +1,201 @@
+use crate::archive::{self, ContentDigest};
+use crate::args::{Args, IndexArgs};
+use crate::collect::{get_datetime_from_archived, read_headers};
+use crate::folder;
+use crate::recover;
+use crate::utils::{self, get_file_name};
+use crate::verify::{to_hex, HashResult, HASH_LEN};
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset};
+use rayon::prelude::*;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// An email in the index of an archive.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub name: String,
+    #[serde(
+        serialize_with = "serialize_hash",
+        deserialize_with = "deserialize_hash"
+    )]
+    pub sha512: HashResult,
+    pub size: u64,
+    /// Date of the email in RFC 3339 format.
+    pub date: Option<String>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub subject: Option<String>,
+    pub message_id: Option<String>,
+}
+
+fn serialize_hash<S: Serializer>(hash: &HashResult, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&to_hex(hash))
+}
+
+fn deserialize_hash<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashResult, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    let mut hash = [0; HASH_LEN];
+    if hex.len() != HASH_LEN * 2 || !hex.is_ascii() {
+        return Err(de::Error::custom("invalid hash"));
+    }
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(de::Error::custom)?;
+    }
+    Ok(hash)
+}
+
+impl IndexEntry {
+    /// Builds the index entry of an email from its name and the digest of
+    /// its content.
+    pub fn new(path: &Path, digest: &ContentDigest) -> io::Result<Self> {
+        let headers = read_headers(&digest.head[..])?;
+        let header = |name: &[u8]| {
+            let value = headers.get(name)?;
+            Some(String::from_utf8_lossy(value).into_owned())
+        };
+        Ok(IndexEntry {
+            name: get_file_name(path).to_string_lossy().into_owned(),
+            sha512: digest.hash,
+            size: digest.size,
+            date: get_datetime_from_archived(path, &headers).map(|date| date.to_rfc3339()),
+            from: header(b"from"),
+            to: header(b"to"),
+            subject: header(b"subject"),
+            message_id: header(b"message-id"),
+        })
+    }
+
+    pub fn datetime(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(self.date.as_deref()?).ok()
+    }
+}
+
+/// The sidecar index of an archive, which lists its emails so that they can
+/// be looked up without decompressing the archive.
+#[derive(Serialize, Deserialize)]
+struct ArchiveIndex {
+    /// Size and modification time in nanoseconds of the archive when the
+    /// index was written, which tell whether the index is stale.
+    archive_size: u64,
+    archive_mtime: u64,
+    entries: Vec<IndexEntry>,
+}
+
+fn get_stamp(archive_path: &Path) -> io::Result<(u64, u64)> {
+    let metadata = fs::metadata(archive_path)?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    Ok((metadata.len(), mtime))
+}
+
+/// Returns the path of the index of the given archive.
+pub fn get_index_path(archive_path: &Path) -> PathBuf {
+    let mut index_path = archive_path.as_os_str().to_os_string();
+    index_path.push(".idx");
+    PathBuf::from(index_path)
+}
+
+/// Reads the index of the archive. Returns None if the index doesn't exist,
+/// is broken, or is stale, i.e. the archive has changed since the index was
+/// written.
+pub fn read_index(archive_path: &Path) -> Option<Vec<IndexEntry>> {
+    let file = File::open(get_index_path(archive_path)).ok()?;
+    let index: ArchiveIndex = serde_json::from_reader(BufReader::new(file)).ok()?;
+    let stamp = get_stamp(archive_path).ok()?;
+    if (index.archive_size, index.archive_mtime) != stamp {
+        return None;
+    }
+    Some(index.entries)
+}
+
+/// Builds the index entries by reading the whole archive.
+pub fn build_index(archive_path: &Path) -> Result<Vec<IndexEntry>> {
+    let mut entries = vec![];
+    archive::read_entries(File::open(archive_path)?, |mut entry| {
+        let digest = entry.digest()?;
+        entries.push(IndexEntry::new(&entry.path, &digest)?);
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// Reads the index of the archive, or builds the entries from the archive
+/// if there is no valid index.
+pub fn read_or_build_index(archive_path: &Path) -> Result<Vec<IndexEntry>> {
+    match read_index(archive_path) {
+        Some(entries) => Ok(entries),
+        None => build_index(archive_path),
+    }
+}
+
+/// Writes the index of the archive, stamped with the current state of the
+/// archive. It is written into a temporary file and then renamed, so the
+/// index is never partially written. If `sync` is set, the index is flushed
+/// to disk before the rename, and the directory after it.
+pub fn write_index(archive_path: &Path, entries: Vec<IndexEntry>, sync: bool) -> Result<()> {
+    let (archive_size, archive_mtime) = get_stamp(archive_path)?;
+    let index = ArchiveIndex {
+        archive_size,
+        archive_mtime,
+        entries,
+    };
+    let index_path = get_index_path(archive_path);
+    let mut tmp_path = index_path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, &index)?;
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    if sync {
+        file.sync_all()?;
+    }
+    drop(file);
+    fs::rename(&tmp_path, &index_path)?;
+    if sync {
+        utils::sync_dir(index_path.parent().unwrap())?;
+    }
+    Ok(())
+}
+
+/// Removes the index of the archive if any.
+pub fn remove_index(archive_path: &Path) {
+    // It's okay if it fails, since the index may not exist.
+    let _ = fs::remove_file(get_index_path(archive_path));
+}
+
+/// Writes indexes of all archives which have no valid index, or of all
+/// archives if `--force` is set.
+pub fn rebuild_indexes(args: &Args, index_args: &IndexArgs) -> Result<()> {
+    let _lock = recover::lock_and_recover(args)?;
+    let archives = folder::list_all_archives(args)?;
+    let progress = utils::create_progress_bar(args, archives.len());
+    let rebuilt = archives
+        .par_iter()
+        .map(|path| {
+            let result = if index_args.force || read_index(path).is_none() {
+                build_index(path)
+                    .and_then(|entries| write_index(path, entries, !args.no_sync))
+                    .with_context(|| format!("failed to index {}", path.display()))
+                    .map(|_| 1)
+            } else {
+                Ok(0)
+            };
+            progress.inc(1);
+            result
+        })
+        .collect::<Result<Vec<_>>>()?;
+    progress.finish_and_clear();
+    if !args.quiet {
+        eprintln!(
+            "Indexed {} of {} archive(s)",
+            rebuilt.iter().sum::<usize>(),
+            archives.len()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,126 @@
+use crate::args::{Args, ListArgs};
+use crate::folder;
+use crate::index;
+use crate::utils;
+use crate::verify::to_hex;
+use anyhow::{Context, Result};
+use chrono::{DateTime, FixedOffset};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+/// An email in an archive.
+#[derive(Serialize)]
+struct EntryInfo {
+    name: String,
+    size: u64,
+    /// SHA-512 hash of the email in hexadecimal.
+    sha512: String,
+    /// Date of the email in RFC 3339 format.
+    date: Option<String>,
+}
+
+/// Summary of an archive.
+#[derive(Serialize)]
+struct ArchiveInfo {
+    /// Path of the archive relative to the packed directory.
+    path: String,
+    emails: usize,
+    compressed_size: u64,
+    /// Total size of the emails in the archive.
+    uncompressed_size: u64,
+    /// Dates of the earliest and the latest emails in RFC 3339 format.
+    first_date: Option<String>,
+    last_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    entries: Option<Vec<EntryInfo>>,
+}
+
+fn read_archive_info(packed_dir: &Path, path: &Path, list_entries: bool) -> Result<ArchiveInfo> {
+    let compressed_size = fs::metadata(path)?.len();
+    let index_entries = index::read_or_build_index(path)?;
+    let emails = index_entries.len();
+    let mut uncompressed_size = 0;
+    let mut range: Option<(DateTime<FixedOffset>, DateTime<FixedOffset>)> = None;
+    let mut entries = vec![];
+    for entry in index_entries {
+        uncompressed_size += entry.size;
+        if let Some(date) = entry.datetime() {
+            range = Some(match range {
+                Some((first, last)) => (first.min(date), last.max(date)),
+                None => (date, date),
+            });
+        }
+        if list_entries {
+            entries.push(EntryInfo {
+                name: entry.name,
+                size: entry.size,
+                sha512: to_hex(&entry.sha512),
+                date: entry.date,
+            });
+        }
+    }
+
+    let relative_path = path.strip_prefix(packed_dir).unwrap_or(path);
+    Ok(ArchiveInfo {
+        path: relative_path.to_string_lossy().into_owned(),
+        emails,
+        compressed_size,
+        uncompressed_size,
+        first_date: range.map(|(first, _)| first.to_rfc3339()),
+        last_date: range.map(|(_, last)| last.to_rfc3339()),
+        entries: list_entries.then_some(entries),
+    })
+}
+
+fn print_archive_info(info: &ArchiveInfo) {
+    let mut line = format!(
+        "{}: {} email(s), {} bytes ({} uncompressed)",
+        info.path, info.emails, info.compressed_size, info.uncompressed_size
+    );
+    if let (Some(first), Some(last)) = (&info.first_date, &info.last_date) {
+        line += &format!(", {} to {}", first, last);
+    }
+    println!("{}", line);
+    for entry in info.entries.iter().flatten() {
+        println!("  {} {} {}", entry.name, entry.size, entry.sha512);
+    }
+}
+
+/// Prints every archive in the packed directory, including those of
+/// subfolders, with their number of emails, sizes and date ranges.
+pub fn print_list(args: &Args, list_args: &ListArgs) -> Result<()> {
+    let archives = folder::list_all_archives(args)?;
+
+    let progress = utils::create_progress_bar(args, archives.len());
+    let infos = archives
+        .par_iter()
+        .map(|path| {
+            let info = read_archive_info(&args.packed_dir, path, list_args.entries)
+                .with_context(|| format!("failed to read {}", path.display()));
+            progress.inc(1);
+            info
+        })
+        .collect::<Result<Vec<_>>>()?;
+    progress.finish_and_clear();
+
+    if list_args.json {
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &infos)?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+    for info in &infos {
+        print_archive_info(info);
+    }
+    println!(
+        "Total: {} archive(s), {} email(s), {} bytes ({} uncompressed)",
+        infos.len(),
+        infos.iter().map(|info| info.emails).sum::<usize>(),
+        infos.iter().map(|info| info.compressed_size).sum::<u64>(),
+        infos.iter().map(|info| info.uncompressed_size).sum::<u64>(),
+    );
+    Ok(())
+}
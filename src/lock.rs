@@ -0,0 +1,68 @@
+use crate::args::Args;
+use anyhow::{bail, Context, Result};
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::process;
+
+/// An exclusive lock on the packed directory, which is released when
+/// dropped. The lock file contains the process ID of the holder.
+///
+/// The lock is an advisory `flock`, so it is released by the system when the
+/// holder dies. A process ID left in the lock file without the lock being
+/// held means that the previous run didn't exit normally.
+pub struct PackedLock {
+    file: File,
+}
+
+impl Drop for PackedLock {
+    fn drop(&mut self) {
+        // Clear the process ID before the lock is released.
+        let _ = self.file.set_len(0);
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut content = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut content).ok()?;
+    content.trim().parse().ok()
+}
+
+/// Takes the lock of the packed directory, waiting for other runs to finish
+/// if `--wait` is set, or failing otherwise.
+pub fn lock_packed_dir(args: &Args) -> Result<PackedLock> {
+    let path = args.maildir.join("packed.lock");
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+        .with_context(|| format!("failed to open lock file {}", path.display()))?;
+    if file.try_lock_exclusive().is_err() {
+        let holder = match read_pid(&mut file) {
+            Some(pid) => format!("process {}", pid),
+            None => "another process".to_string(),
+        };
+        if !args.wait {
+            bail!("{} is locked by {}", args.packed_dir.display(), holder);
+        }
+        if !args.quiet {
+            eprintln!("Waiting for {} to release the lock...", holder);
+        }
+        file.lock_exclusive()
+            .with_context(|| format!("failed to lock {}", path.display()))?;
+    }
+
+    if let Some(pid) = read_pid(&mut file) {
+        eprintln!(
+            "Warning: found stale lock of process {}, which didn't exit normally",
+            pid
+        );
+    }
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "{}", process::id())?;
+    Ok(PackedLock { file })
+}
@@ -1,35 +1,129 @@
+mod archive;
 mod args;
 mod classify;
 mod collect;
+mod compact;
+mod compress;
 mod datetime;
 mod execute;
+mod folder;
+mod index;
+mod list;
+mod lock;
+mod mbox;
+mod mh;
+mod plan;
+mod rebucket;
+mod recover;
+mod search;
+mod template;
+mod unpack;
 mod utils;
 mod verify;
 
-use crate::args::Args;
+use crate::args::{Args, Command};
 use anyhow::Result;
 use std::fs;
+use std::process::ExitCode;
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     let args = Args::parse_args();
 
+    match &args.command {
+        Some(Command::List(list_args)) => {
+            list::print_list(&args, list_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Unpack(unpack_args)) => {
+            unpack::unpack(&args, unpack_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Search(search_args)) => {
+            search::search(&args, search_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Index(index_args)) => {
+            index::rebuild_indexes(&args, index_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        Some(Command::Compact(compact_args)) => {
+            compact::compact(&args, compact_args)?;
+            return Ok(ExitCode::SUCCESS);
+        }
+        None => {}
+    }
+
     macro_rules! report {
-        ($msg:expr) => {
+        ($($arg:tt)*) => {
             if !args.quiet {
-                eprintln!($msg);
+                eprintln!($($arg)*);
             }
         };
     }
 
-    report!("Listing emails...");
-    let list = collect::list_emails(&args)?;
+    // The lock is held until all folders are packed.
+    let _lock = if args.dry_run {
+        None
+    } else {
+        Some(recover::lock_and_recover(&args)?)
+    };
+
+    let mut failure = None;
+    for folder in folder::list_folders(&args)? {
+        if args.recursive {
+            if folder.name.is_empty() {
+                report!("Packing root folder...");
+            } else {
+                report!("Packing folder {}...", folder.name);
+            }
+        }
+
+        if args.rebucket {
+            report!("Unpacking existing archives...");
+            rebucket::unpack_archives(&args, &folder)?;
+        }
 
-    report!("Classifying emails...");
-    let map = classify::classify_emails(list);
+        let mut imported = vec![];
+        if folder.name.is_empty() && !args.mbox.is_empty() {
+            report!("Splitting mbox files...");
+            let messages = collect::import_mbox_files(&args, &folder)?;
+            report!("Found {} message(s) in mbox files", messages.len());
+            imported.extend(messages);
+        }
 
-    report!("Archiving emails...");
-    fs::create_dir_all(&args.packed_dir)?;
-    execute::archive_emails(&args, map);
+        if folder.name.is_empty() && !args.mh.is_empty() {
+            report!("Reading MH folders...");
+            let messages = collect::import_mh_folders(&args, &folder)?;
+            report!("Found {} message(s) in MH folders", messages.len());
+            imported.extend(messages);
+        }
+
+        report!("Listing emails...");
+        let list = collect::list_emails(&args, &folder, imported)?;
+        collect::report_date_sources(&args, &list);
+
+        report!("Classifying emails...");
+        let unknown = list.iter().filter(|email| email.datetime.is_none()).count();
+        let map = classify::classify_emails(&args, &folder, list);
+
+        if args.dry_run {
+            report!("Reading existing archives...");
+            plan::print_plan(&args, &folder, map, unknown)?;
+            continue;
+        }
+
+        report!("Archiving emails...");
+        if !map.is_empty() {
+            fs::create_dir_all(&folder.packed_dir)?;
+        }
+        let outcomes = execute::archive_emails(&args, &folder, map)?;
+        failure = failure.max(execute::report_outcomes(&args, &outcomes));
+        rebucket::remove_staging_dir(&folder);
+        collect::remove_import_dir(&folder);
+    }
 
-    Ok(())
+    Ok(match failure {
+        Some(failure) => ExitCode::from(failure.exit_code()),
+        None => ExitCode::SUCCESS,
+    })
 }
@@ -0,0 +1,406 @@
+use crate::collect::Headers;
+use chrono::{DateTime, FixedOffset};
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::io::{self, BufRead, ErrorKind, Write};
+
+const FROM_LINE: &[u8] = b"From ";
+
+/// Variants of the mbox format, which differ in how the end of a message is
+/// found and how `From ` lines in messages are quoted.
+/// https://www.loc.gov/preservation/digital/formats/fdd/fdd000383.shtml
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MboxFormat {
+    /// `From ` lines in messages are quoted as `>From `.
+    Mboxo,
+    /// `From ` lines in messages, quoted or not, are quoted by another `>`.
+    Mboxrd,
+    /// Quoted as mboxo, with the length of the body in `Content-Length`.
+    Mboxcl,
+    /// The length of the body is in `Content-Length` without quoting.
+    Mboxcl2,
+}
+
+impl MboxFormat {
+    /// Removes the quoting `>` from a line of a message.
+    fn unquote(self, line: &mut Vec<u8>) {
+        let quotes = line.iter().take_while(|&&b| b == b'>').count();
+        let quoted = match self {
+            MboxFormat::Mboxrd => quotes > 0,
+            MboxFormat::Mboxo | MboxFormat::Mboxcl => quotes == 1,
+            MboxFormat::Mboxcl2 => false,
+        };
+        if quoted && line[quotes..].starts_with(FROM_LINE) {
+            line.remove(0);
+        }
+    }
+
+    fn has_content_length(self) -> bool {
+        matches!(self, MboxFormat::Mboxcl | MboxFormat::Mboxcl2)
+    }
+}
+
+fn is_blank(line: &[u8]) -> bool {
+    line == b"\n" || line == b"\r\n"
+}
+
+/// Reader which splits an mbox file into messages. Each message keeps its
+/// `From ` line, and the quoting of `From ` lines is removed.
+pub struct MboxReader<R> {
+    reader: R,
+    format: MboxFormat,
+    /// Lines which have been read ahead.
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl<R: BufRead> MboxReader<R> {
+    pub fn new(reader: R, format: MboxFormat) -> Self {
+        MboxReader {
+            reader,
+            format,
+            pending: VecDeque::new(),
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Some(line) = self.pending.pop_front() {
+            return Ok(Some(line));
+        }
+        let mut line = vec![];
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line))
+    }
+
+    /// Reads the next message, or returns None at the end of the file.
+    pub fn read_message(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut message = loop {
+            match self.read_line()? {
+                Some(line) if is_blank(&line) => continue,
+                Some(line) if line.starts_with(FROM_LINE) => break line,
+                Some(_) => return Err(io::Error::new(ErrorKind::InvalidData, "not an mbox file")),
+                None => return Ok(None),
+            }
+        };
+
+        // Read the header section, which ends with an empty line.
+        let mut content_length = None;
+        loop {
+            let mut line = match self.read_line()? {
+                Some(line) => line,
+                None => return Ok(Some(message)),
+            };
+            if is_blank(&line) {
+                message.extend(line);
+                break;
+            }
+            const CONTENT_LENGTH: &[u8] = b"content-length:";
+            if line.len() > CONTENT_LENGTH.len()
+                && line[..CONTENT_LENGTH.len()].eq_ignore_ascii_case(CONTENT_LENGTH)
+            {
+                let value = &line[CONTENT_LENGTH.len()..];
+                content_length = std::str::from_utf8(value)
+                    .ok()
+                    .and_then(|value| value.trim().parse().ok());
+            }
+            self.format.unquote(&mut line);
+
+            message.extend(line);
+        }
+
+        if let Some(length) = content_length.filter(|_| self.format.has_content_length()) {
+            // Lines may have been read ahead after a wrong length of the
+            // previous message, so the body is read line by line.
+            let mut lines = vec![];
+            let mut read = 0;
+            while read < length {
+                match self.read_line()? {
+                    Some(line) => {
+                        read += line.len() as u64;
+                        lines.push(line);
+                    }
+                    None => break,
+                }
+            }
+            let complete = read == length;
+            if complete && self.ends_after_length()? {
+                for mut line in lines {
+                    self.format.unquote(&mut line);
+                    message.extend(line);
+                }
+                return Ok(Some(message));
+            }
+            // The length is wrong, so the message ends before the next
+            // `From ` line as in the other formats.
+            for line in lines.into_iter().rev() {
+                self.pending.push_front(line);
+            }
+        }
+
+        while let Some(mut line) = self.read_line()? {
+            let after_blank = message.ends_with(b"\n\n") || message.ends_with(b"\n\r\n");
+            if after_blank && line.starts_with(FROM_LINE) {
+                self.pending.push_front(line);
+                break;
+            }
+            self.format.unquote(&mut line);
+            message.extend(line);
+        }
+        // Remove the empty line which separates messages.
+        if message.ends_with(b"\r\n\r\n") {
+            message.truncate(message.len() - 2);
+        } else if message.ends_with(b"\n\n") {
+            message.pop();
+        }
+        Ok(Some(message))
+    }
+
+    /// Checks whether the message ends after the body of `Content-Length`,
+    /// i.e. it is followed by an optional empty line and the next message.
+    /// Lines read ahead are kept for reading unless they are the empty line
+    /// between messages.
+    fn ends_after_length(&mut self) -> io::Result<bool> {
+        let line = match self.read_line()? {
+            Some(line) => line,
+            None => return Ok(true),
+        };
+        if !is_blank(&line) {
+            let ends = line.starts_with(FROM_LINE);
+            self.pending.push_front(line);
+            return Ok(ends);
+        }
+        match self.read_line()? {
+            Some(next) => {
+                let ends = next.starts_with(FROM_LINE);
+                self.pending.push_front(next);
+                if !ends {
+                    self.pending.push_front(line);
+                }
+                Ok(ends)
+            }
+            None => Ok(true),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for MboxReader<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_message().transpose()
+    }
+}
+
+/// Extracts the address from a header value like `Name <addr>` or `addr`.
+fn extract_address(value: &[u8]) -> Option<&[u8]> {
+    let value = match value.iter().rposition(|&b| b == b'<') {
+        Some(start) => {
+            let rest = &value[start + 1..];
+            let end = rest.iter().position(|&b| b == b'>')?;
+            &rest[..end]
+        }
+        None => value
+            .split(|b| b.is_ascii_whitespace())
+            .find(|s| !s.is_empty())?,
+    };
+    Some(value).filter(|value| !value.is_empty() && !value.iter().any(u8::is_ascii_whitespace))
+}
+
+/// Builds the content of the `From ` line of an email after `From `, i.e.
+/// the envelope sender and the date in asctime format. The existing `From `
+/// line is kept if the email has one. Otherwise the sender comes from the
+/// `Return-Path` or the `From` header.
+pub fn get_from_line(headers: &Headers, date: Option<&DateTime<FixedOffset>>) -> Vec<u8> {
+    if let Some(from_line) = headers.mbox_from_line() {
+        return from_line.to_vec();
+    }
+    let sender = match headers.get(b"return-path") {
+        // An empty Return-Path, i.e. `<>`, is used for bounces.
+        Some(value) => extract_address(value).unwrap_or(b"MAILER-DAEMON"),
+        None => headers
+            .get(b"from")
+            .and_then(extract_address)
+            .unwrap_or(b"MAILER-DAEMON"),
+    };
+    let date = match date {
+        Some(date) => date.naive_utc(),
+        None => Default::default(),
+    };
+    let mut result = sender.to_vec();
+    write!(result, " {}", date.format("%a %b %e %H:%M:%S %Y")).unwrap();
+    result
+}
+
+/// Splits the `From ` line off an email for mbox archives, and returns it
+/// without `From ` and the line break, along with the rest of the content
+/// which always ends with a newline.
+pub fn split_from_line(mut content: Vec<u8>) -> (Option<Vec<u8>>, Vec<u8>) {
+    let mut from_line = None;
+    if starts_with_from_line(&content) {
+        let pos = content
+            .iter()
+            .position(|&b| b == b'\n')
+            .unwrap_or(content.len());
+        let mut line: Vec<u8> = content.drain(..(pos + 1).min(content.len())).collect();
+        while line.last().is_some_and(|&b| b == b'\n' || b == b'\r') {
+            line.pop();
+        }
+        line.drain(..FROM_LINE.len());
+        from_line = Some(line);
+    }
+    if !content.is_empty() && !content.ends_with(b"\n") {
+        content.push(b'\n');
+    }
+    (from_line, content)
+}
+
+/// Whether the email starts with a `From ` line, rather than a `From`
+/// header field with whitespaces before the colon in obsolete syntax.
+fn starts_with_from_line(content: &[u8]) -> bool {
+    match content.strip_prefix(FROM_LINE) {
+        Some(rest) => rest.iter().find(|&&b| b != b' ' && b != b'\t') != Some(&b':'),
+        None => false,
+    }
+}
+
+/// Writes an email in mboxrd format, i.e. the `From ` line, the content with
+/// `From ` lines quoted by prepending `>`, and an empty line. The `From `
+/// line in the content is skipped if any.
+/// https://www.loc.gov/preservation/digital/formats/fdd/fdd000385.shtml
+pub fn write_message(w: &mut impl Write, from_line: &[u8], content: &[u8]) -> io::Result<()> {
+    w.write_all(FROM_LINE)?;
+    w.write_all(from_line)?;
+    w.write_all(b"\n")?;
+    let mut lines = content.split_inclusive(|&b| b == b'\n').peekable();
+    if starts_with_from_line(content) {
+        lines.next();
+    }
+    let mut last = &b""[..];
+    for line in lines {
+        let quotes = line.iter().take_while(|&&b| b == b'>').count();
+        if line[quotes..].starts_with(FROM_LINE) {
+            w.write_all(b">")?;
+        }
+        w.write_all(line)?;
+        last = line;
+    }
+    if !last.is_empty() && !last.ends_with(b"\n") {
+        w.write_all(b"\n")?;
+    }
+    w.write_all(b"\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collect::read_headers;
+
+    fn from_line(content: &[u8], date: Option<&str>) -> String {
+        let headers = read_headers(content).unwrap();
+        let date = date.map(|date| DateTime::parse_from_rfc3339(date).unwrap());
+        String::from_utf8(get_from_line(&headers, date.as_ref())).unwrap()
+    }
+
+    #[test]
+    fn test_get_from_line() {
+        let date = Some("2017-06-30T20:00:00-04:00");
+        assert_eq!(
+            from_line(
+                b"Return-Path: <a@example.com>\nFrom: b@example.com\n\n",
+                date
+            ),
+            "a@example.com Sat Jul  1 00:00:00 2017"
+        );
+        assert_eq!(
+            from_line(b"From: Someone <b@example.com>\n\n", date),
+            "b@example.com Sat Jul  1 00:00:00 2017"
+        );
+        assert_eq!(
+            from_line(b"Return-Path: <>\n\n", date),
+            "MAILER-DAEMON Sat Jul  1 00:00:00 2017"
+        );
+        assert_eq!(
+            from_line(b"Subject: none\n\n", None),
+            "MAILER-DAEMON Thu Jan  1 00:00:00 1970"
+        );
+        assert_eq!(
+            from_line(
+                b"From c@example.com Mon Jan  2 03:04:05 2006\nFrom: b\n\n",
+                date
+            ),
+            "c@example.com Mon Jan  2 03:04:05 2006"
+        );
+    }
+
+    fn split(mbox: &[u8], format: MboxFormat) -> Vec<String> {
+        MboxReader::new(mbox, format)
+            .map(|message| String::from_utf8(message.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_read_mboxrd() {
+        let mbox = b"From a Mon Jan  2 03:04:05 2006\nSubject: a\n\n>From here\n>>From there\nFrom nowhere\n\n\
+            From b Mon Jan  2 03:04:05 2006\nSubject: b\n\nbody\n\n";
+        assert_eq!(
+            split(mbox, MboxFormat::Mboxrd),
+            [
+                "From a Mon Jan  2 03:04:05 2006\nSubject: a\n\nFrom here\n>From there\nFrom nowhere\n",
+                "From b Mon Jan  2 03:04:05 2006\nSubject: b\n\nbody\n",
+            ]
+        );
+        assert_eq!(
+            split(mbox, MboxFormat::Mboxo)[0],
+            "From a Mon Jan  2 03:04:05 2006\nSubject: a\n\nFrom here\n>>From there\nFrom nowhere\n"
+        );
+        assert!(MboxReader::new(&b"Subject: a\n\n"[..], MboxFormat::Mboxrd)
+            .next()
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn test_read_mboxcl2() {
+        let mbox =
+            b"From a Mon Jan  2 03:04:05 2006\nContent-Length: 22\n\nbody\n\nFrom here\n>From\n\n\
+            From b Mon Jan  2 03:04:05 2006\nContent-Length: 100\n\nbody\n\n\
+            From c Mon Jan  2 03:04:05 2006\n\nbody\n";
+        assert_eq!(
+            split(mbox, MboxFormat::Mboxcl2),
+            [
+                "From a Mon Jan  2 03:04:05 2006\nContent-Length: 22\n\nbody\n\nFrom here\n>From\n",
+                // The wrong length is ignored.
+                "From b Mon Jan  2 03:04:05 2006\nContent-Length: 100\n\nbody\n",
+                "From c Mon Jan  2 03:04:05 2006\n\nbody\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_read_mboxcl2_after_wrong_length() {
+        // The wrong length of b runs into c, whose length is right.
+        let mbox = b"From b Mon Jan  2 03:04:05 2006\nContent-Length: 40\n\nline1\n\n\
+            From c Mon Jan  2 03:04:05 2006\nContent-Length: 17\n\nline2\nFrom line1\n\n\
+            From d Mon Jan  2 03:04:05 2006\n\nbody\n";
+        assert_eq!(
+            split(mbox, MboxFormat::Mboxcl2),
+            [
+                "From b Mon Jan  2 03:04:05 2006\nContent-Length: 40\n\nline1\n",
+                "From c Mon Jan  2 03:04:05 2006\nContent-Length: 17\n\nline2\nFrom line1\n",
+                "From d Mon Jan  2 03:04:05 2006\n\nbody\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_message() {
+        let mut output = vec![];
+        let content = b"From x Mon Jan  2 03:04:05 2006\nSubject: a\n\nFrom here\n>From there\nend";
+        write_message(&mut output, b"x Mon Jan  2 03:04:05 2006", content).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "From x Mon Jan  2 03:04:05 2006\nSubject: a\n\n>From here\n>>From there\nend\n\n"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+/// Name of the file which holds the public sequences of an MH folder.
+const SEQUENCES_FILE: &str = ".mh_sequences";
+
+/// Lists messages in the MH folder, which are files named by a positive
+/// number, in the order of their numbers. Other files, e.g. messages removed
+/// by renaming them to `,123`, and subfolders are skipped.
+pub fn list_messages(dir: &Path) -> io::Result<Vec<(u32, PathBuf)>> {
+    let mut messages = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let number = match entry.file_name().to_str().and_then(parse_message_number) {
+            Some(number) => number,
+            None => continue,
+        };
+        if entry.file_type()?.is_file() {
+            messages.push((number, entry.path()));
+        }
+    }
+    messages.sort_unstable();
+    Ok(messages)
+}
+
+fn parse_message_number(name: &str) -> Option<u32> {
+    if !name.bytes().all(|b| b.is_ascii_digit()) || name.starts_with('0') {
+        return None;
+    }
+    name.parse().ok()
+}
+
+/// Reads the public sequences of the MH folder, which map their names to
+/// message numbers. A folder without sequences has an empty map.
+/// https://www.nongnu.org/nmh/
+pub fn read_sequences(dir: &Path) -> io::Result<HashMap<String, HashSet<u32>>> {
+    match fs::read_to_string(dir.join(SEQUENCES_FILE)) {
+        Ok(content) => Ok(parse_sequences(&content)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Parses sequences in lines like `unseen: 1-3 7`. A line starting with
+/// whitespace continues the previous one. Malformed items are ignored.
+fn parse_sequences(content: &str) -> HashMap<String, HashSet<u32>> {
+    let mut sequences = HashMap::new();
+    let mut current: Option<&mut HashSet<u32>> = None;
+    for line in content.lines() {
+        let items = if line.starts_with(|c: char| c.is_ascii_whitespace()) {
+            line
+        } else {
+            match line.split_once(':') {
+                Some((name, items)) => {
+                    let numbers = sequences
+                        .entry(name.trim().to_string())
+                        .or_insert_with(HashSet::new);
+                    current = Some(numbers);
+                    items
+                }
+                None => {
+                    current = None;
+                    continue;
+                }
+            }
+        };
+        let numbers = match current.as_mut() {
+            Some(numbers) => numbers,
+            None => continue,
+        };
+        for item in items.split_ascii_whitespace() {
+            let range: Option<(u32, u32)> = match item.split_once('-') {
+                Some((first, last)) => first.parse().ok().zip(last.parse().ok()),
+                None => item.parse().ok().map(|number| (number, number)),
+            };
+            if let Some((first, last)) = range {
+                numbers.extend(first..=last);
+            }
+        }
+    }
+    sequences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sequences() {
+        let sequences = parse_sequences("unseen: 1-3 7\ncur: 5\nflagged: 2\n 9-10 x\nbroken\n");
+        let expected: HashMap<_, HashSet<_>> = [
+            ("unseen", vec![1, 2, 3, 7]),
+            ("cur", vec![5]),
+            ("flagged", vec![2, 9, 10]),
+        ]
+        .iter()
+        .map(|(name, numbers)| (name.to_string(), numbers.iter().copied().collect()))
+        .collect();
+        assert_eq!(sequences, expected);
+    }
+
+    #[test]
+    fn test_parse_message_number() {
+        assert_eq!(parse_message_number("1"), Some(1));
+        assert_eq!(parse_message_number("120"), Some(120));
+        assert_eq!(parse_message_number(",12"), None);
+        assert_eq!(parse_message_number("012"), None);
+        assert_eq!(parse_message_number("12.bak"), None);
+        assert_eq!(parse_message_number(""), None);
+    }
+}
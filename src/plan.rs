@@ -0,0 +1,135 @@
+use crate::args::Args;
+use crate::collect::Email;
+use crate::execute::{find_existing_archive, read_archive_hashes, read_email};
+use crate::folder::Folder;
+use crate::index;
+use crate::utils;
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
+use std::path::PathBuf;
+
+/// What would happen to an archive when packing.
+struct ArchivePlan {
+    /// Path of the archive relative to the packed directory.
+    path: PathBuf,
+    /// Whether the archive exists already.
+    exists: bool,
+    /// Number of emails to be added.
+    new: usize,
+    /// Number of emails in the archive already with identical content.
+    present: usize,
+    /// Number of emails in the archive already but with different content.
+    conflicting: usize,
+}
+
+fn plan_archive(args: &Args, folder: &Folder, name: &str, emails: &[Email]) -> Result<ArchivePlan> {
+    let archive_path = folder
+        .packed_dir
+        .join(format!("{}{}", name, args.archive_kind().suffix()));
+    let existing_path = find_existing_archive(args, folder, name);
+    let mut existing_files = match &existing_path {
+        Some(path) => match index::read_index(path) {
+            Some(entries) => entries
+                .into_iter()
+                .map(|entry| (OsString::from(entry.name), entry.sha512))
+                .collect(),
+            None => read_archive_hashes(File::open(path)?)?,
+        },
+        None => HashMap::new(),
+    };
+
+    let mut plan = ArchivePlan {
+        path: archive_path
+            .strip_prefix(&args.packed_dir)
+            .unwrap_or(&archive_path)
+            .to_path_buf(),
+        exists: existing_path.is_some(),
+        new: 0,
+        present: 0,
+        conflicting: 0,
+    };
+    for email in emails {
+        // Messages imported in memory by the dry run have no file to read.
+        let (file_name, hash) = match &email.imported {
+            Some(imported) => (imported.entry_name.clone(), imported.hash),
+            None => {
+                let mut entry = read_email(args.format, email)?;
+                let hash = entry.digest()?.hash;
+                (entry.path.into_os_string(), hash)
+            }
+        };
+        match existing_files.get(&file_name) {
+            Some(expected_hash) if expected_hash[..] == hash[..] => plan.present += 1,
+            Some(_) => plan.conflicting += 1,
+            None => {
+                plan.new += 1;
+                existing_files.insert(file_name, hash);
+            }
+        }
+    }
+    Ok(plan)
+}
+
+fn print_archive_plan(plan: &ArchivePlan) {
+    let action = if plan.exists { "update" } else { "create" };
+    let mut line = format!(
+        "{}: {}, {} new email(s)",
+        plan.path.display(),
+        action,
+        plan.new
+    );
+    if plan.present > 0 {
+        line.push_str(&format!(", {} already archived", plan.present));
+    }
+    if plan.conflicting > 0 {
+        line.push_str(&format!(
+            ", {} archived with different content",
+            plan.conflicting
+        ));
+    }
+    println!("{}", line);
+}
+
+/// Prints what packing would do to each archive, without modifying anything.
+/// `unknown` is the number of emails without a date.
+pub fn print_plan(
+    args: &Args,
+    folder: &Folder,
+    map: HashMap<String, Vec<Email>>,
+    unknown: usize,
+) -> Result<()> {
+    let progress = utils::create_progress_bar(args, map.len());
+    progress.tick();
+    let mut plans = map
+        .par_iter()
+        .map(|(name, emails)| {
+            let plan = plan_archive(args, folder, name, emails)
+                .with_context(|| format!("failed to read archive {}", name));
+            progress.inc(1);
+            plan
+        })
+        .collect::<Result<Vec<_>>>()?;
+    progress.finish_and_clear();
+
+    plans.sort_by(|a, b| a.path.cmp(&b.path));
+    for plan in &plans {
+        print_archive_plan(plan);
+    }
+    let folder_name = match folder.name.as_str() {
+        "" => "INBOX",
+        name => name,
+    };
+    let (updated, created): (Vec<_>, Vec<_>) = plans.iter().partition(|plan| plan.exists);
+    println!(
+        "{}: {} archive(s) to create, {} to update, {} email(s) to add, {} without date",
+        folder_name,
+        created.len(),
+        updated.len(),
+        plans.iter().map(|plan| plan.new).sum::<usize>(),
+        unknown,
+    );
+    Ok(())
+}
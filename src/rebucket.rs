@@ -0,0 +1,108 @@
+use crate::archive::{self, ArchiveKind};
+use crate::args::Args;
+use crate::execute::get_backup_path;
+use crate::folder::Folder;
+use crate::index;
+use crate::utils::{self, get_file_name};
+use crate::verify::hash_file;
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+
+/// Unpacks entries of the given archive into the staging directory. Returns
+/// whether all entries are unpacked, i.e. it is safe to remove the archive.
+/// Unpacked emails are flushed to disk unless --no-sync is set.
+fn unpack_archive(args: &Args, archive_path: &Path, staging_dir: &Path) -> Result<bool> {
+    let file = File::open(archive_path)?;
+    let mut complete = true;
+    archive::read_entries(file, |mut entry| {
+        let file_name = get_file_name(&entry.path).to_os_string();
+        // Write into a hidden file first, so that a partially unpacked email
+        // is never picked up.
+        let mut part_name = OsString::from(".");
+        part_name.push(&file_name);
+        part_name.push(".part");
+        let part_path = staging_dir.join(part_name);
+        let mut write_part = || -> io::Result<()> {
+            let mut part_file = File::create(&part_path)?;
+            io::copy(&mut entry, &mut part_file)?;
+            if !args.no_sync {
+                part_file.sync_all()?;
+            }
+            Ok(())
+        };
+        write_part().with_context(|| format!("failed to unpack {:?}", file_name))?;
+
+        let path = staging_dir.join(&file_name);
+        if let Ok(file) = File::open(&path) {
+            // It may have been unpacked by an interrupted run.
+            if hash_file(file)[..] != entry.digest()?.hash[..] {
+                eprintln!(
+                    "Warning: {:?} in {:?} conflicts with another archive",
+                    file_name,
+                    get_file_name(archive_path)
+                );
+                complete = false;
+            }
+            fs::remove_file(&part_path)?;
+        } else {
+            fs::rename(&part_path, &path)?;
+        }
+        Ok(())
+    })?;
+    Ok(complete)
+}
+
+/// Unpacks all existing archives of the folder into its staging directory
+/// and removes the archives, so that their emails are packed again along
+/// with new emails. Archives with conflicting entries are kept. The staging
+/// directory is flushed to disk before an archive is removed, so that its
+/// emails are never lost.
+pub fn unpack_archives(args: &Args, folder: &Folder) -> Result<()> {
+    let archives: Vec<_> = folder
+        .list_packed_files()?
+        .into_iter()
+        .filter(|path| ArchiveKind::strip_suffix(&get_file_name(path).to_string_lossy()).is_some())
+        .collect();
+    if archives.is_empty() {
+        return Ok(());
+    }
+    let staging_dir = folder.staging_dir();
+    fs::create_dir_all(&staging_dir)?;
+    if !args.no_sync {
+        // The staging directory itself needs to survive a crash as well.
+        utils::sync_dir(&folder.packed_dir)?;
+    }
+    for path in archives {
+        let complete = unpack_archive(args, &path, &staging_dir)
+            .with_context(|| format!("failed to unpack {}", path.display()))?;
+        if complete {
+            if !args.no_sync {
+                utils::sync_dir(&staging_dir)
+                    .with_context(|| format!("failed to sync {}", staging_dir.display()))?;
+            }
+            fs::remove_file(&path)?;
+            // The backup may not exist.
+            let _ = fs::remove_file(get_backup_path(&path));
+            index::remove_index(&path);
+            // Remove directories which become empty.
+            let mut dir = path.parent();
+            while let Some(d) = dir.filter(|&d| d != folder.packed_dir) {
+                if fs::remove_dir(d).is_err() {
+                    break;
+                }
+                dir = d.parent();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes the staging directory of the folder if it is empty.
+pub fn remove_staging_dir(folder: &Folder) {
+    // It's okay if it fails, since it may not exist, or some emails may
+    // not have been packed.
+    let _ = fs::remove_dir(folder.staging_dir());
+}
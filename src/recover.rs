@@ -0,0 +1,225 @@
+use crate::archive::ArchiveKind;
+use crate::args::Args;
+use crate::execute::{get_backup_path, get_unique_name, read_archive_hashes_into, replace_archive};
+use crate::folder::{self, Folder};
+use crate::lock::{self, PackedLock};
+use crate::utils::{self, get_file_name};
+use crate::verify::HashResult;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::path::{Path, PathBuf};
+
+/// Content of an archive file, as far as it can be read.
+struct ArchiveContent {
+    files: HashMap<OsString, HashResult>,
+    /// Whether the whole archive is readable.
+    complete: bool,
+}
+
+impl ArchiveContent {
+    /// Reads the archive at the given path, or returns None if it doesn't
+    /// exist.
+    fn read(path: &Path) -> Option<Self> {
+        let file = File::open(path).ok()?;
+        let mut files = HashMap::new();
+        let complete = read_archive_hashes_into(file, &mut files).is_ok();
+        Some(ArchiveContent { files, complete })
+    }
+
+    /// Whether this archive has every entry of the other with the same content.
+    fn contains(&self, other: &ArchiveContent) -> bool {
+        other
+            .files
+            .iter()
+            .all(|(name, hash)| self.files.get(name).is_some_and(|h| h[..] == hash[..]))
+    }
+}
+
+/// Lists unique names of emails in the maildir which are going to be packed.
+fn list_maildir_names(folder: &Folder) -> HashSet<OsString> {
+    let dirs = [
+        folder.path.join("new"),
+        folder.path.join("cur"),
+        folder.staging_dir(),
+        folder.import_dir(),
+    ];
+    dirs.iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .map(|entry| get_unique_name(&entry.file_name()).to_os_string())
+        .collect()
+}
+
+/// Recovers an archive from a temporary file left by an interrupted run.
+/// The temporary archive is moved into place if it is complete and contains
+/// everything in the existing archive, otherwise it is removed and, if the
+/// existing archive is broken, its backup is restored.
+fn recover_archive(args: &Args, tmp_path: &Path, maildir_names: &HashSet<OsString>) -> Result<()> {
+    let archive_path = tmp_path.with_extension("");
+    let archive_name = get_file_name(&archive_path).to_string_lossy().into_owned();
+    let (name, kind) = match ArchiveKind::split_suffix(&archive_name) {
+        Some(result) => result,
+        None => {
+            // A partially written index, which is rebuilt when needed.
+            if archive_name.ends_with(".idx") {
+                fs::remove_file(tmp_path)?;
+            }
+            return Ok(());
+        }
+    };
+    let existing_path = kind
+        .suffixes()
+        .map(|suffix| archive_path.with_file_name(format!("{}{}", name, suffix)))
+        .find(|path| path.is_file());
+
+    let tmp = ArchiveContent::read(tmp_path);
+    let existing = existing_path.as_deref().and_then(ArchiveContent::read);
+    let backup = existing_path
+        .as_deref()
+        .and_then(|path| ArchiveContent::read(&get_backup_path(path)));
+
+    let roll_forward = match (&tmp, &existing) {
+        (Some(tmp), Some(existing)) => tmp.complete && tmp.contains(existing),
+        (Some(tmp), None) => tmp.complete,
+        (None, _) => false,
+    };
+    let recovered = if roll_forward {
+        // Don't replace the backup with a broken archive.
+        let existing_path = existing_path.filter(|_| existing.as_ref().unwrap().complete);
+        replace_archive(tmp_path, &archive_path, existing_path.as_deref())?;
+        if !args.no_sync {
+            utils::sync_dir(archive_path.parent().unwrap())?;
+        }
+        if !args.quiet {
+            eprintln!(
+                "Recovered {}: finished the interrupted update",
+                archive_name
+            );
+        }
+        tmp.as_ref()
+    } else {
+        fs::remove_file(tmp_path)?;
+        match (&existing_path, &existing) {
+            (Some(_), Some(existing)) if existing.complete => {
+                if !args.quiet {
+                    eprintln!(
+                        "Recovered {}: discarded the interrupted update",
+                        archive_name
+                    );
+                }
+                Some(existing)
+            }
+            (Some(existing_path), _) if backup.as_ref().is_some_and(|b| b.complete) => {
+                let backup_path = get_backup_path(existing_path);
+                fs::rename(&backup_path, existing_path).context("failed to restore backup")?;
+                fs::hard_link(existing_path, &backup_path).context("failed to link backup file")?;
+                if !args.no_sync {
+                    utils::sync_dir(existing_path.parent().unwrap())?;
+                }
+                eprintln!(
+                    "Warning: {} is broken and has been restored from its backup",
+                    get_file_name(existing_path).to_string_lossy()
+                );
+                backup.as_ref()
+            }
+            (Some(existing_path), _) => {
+                eprintln!(
+                    "Warning: {} is broken and has no valid backup",
+                    get_file_name(existing_path).to_string_lossy()
+                );
+                None
+            }
+            (None, _) => None,
+        }
+    };
+
+    // Report emails which are neither in a valid archive nor in the maildir.
+    let mut lost: Vec<_> = [&tmp, &existing, &backup]
+        .iter()
+        .filter_map(|content| content.as_ref())
+        .flat_map(|content| content.files.keys())
+        .filter(|&file_name| !recovered.is_some_and(|r| r.files.contains_key(file_name)))
+        .filter(|&file_name| !maildir_names.contains(file_name))
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    lost.sort();
+    for file_name in lost {
+        eprintln!(
+            "Warning: {:?} of {} may be lost, since it is not in a valid archive",
+            file_name, archive_name
+        );
+    }
+    Ok(())
+}
+
+/// Rolls back an interrupted append by truncating the archive to the size
+/// recorded in the journal. Emails are only removed from the maildir after
+/// the journal is removed, so nothing is lost.
+fn recover_append(args: &Args, journal_path: &Path) -> Result<()> {
+    let archive_path = journal_path.with_extension("");
+    let original_len: u64 = fs::read_to_string(journal_path)?
+        .trim()
+        .parse()
+        .context("broken journal")?;
+    if let Ok(file) = OpenOptions::new().write(true).open(&archive_path) {
+        if file.metadata()?.len() > original_len {
+            file.set_len(original_len)?;
+            if !args.no_sync {
+                file.sync_all()?;
+            }
+        }
+    }
+    fs::remove_file(journal_path)?;
+    if !args.no_sync {
+        utils::sync_dir(archive_path.parent().unwrap())?;
+    }
+    if !args.quiet {
+        eprintln!(
+            "Recovered {}: discarded the interrupted append",
+            get_file_name(&archive_path).to_string_lossy()
+        );
+    }
+    Ok(())
+}
+
+/// Recovers archives of the folder left by interrupted runs, which can be
+/// found from their temporary files and journals.
+pub fn recover_archives(args: &Args, folder: &Folder) -> Result<()> {
+    let packed_files = folder.list_packed_files()?;
+    let has_extension =
+        |path: &PathBuf, extension: &str| path.extension().is_some_and(|ext| ext == extension);
+    for journal_path in packed_files
+        .iter()
+        .filter(|path| has_extension(path, "append"))
+    {
+        recover_append(args, journal_path)
+            .with_context(|| format!("failed to recover {}", journal_path.display()))?;
+    }
+    let tmp_paths: Vec<&PathBuf> = packed_files
+        .iter()
+        .filter(|path| has_extension(path, "tmp"))
+        .collect();
+    if tmp_paths.is_empty() {
+        return Ok(());
+    }
+    let maildir_names = list_maildir_names(folder);
+    for tmp_path in tmp_paths {
+        recover_archive(args, tmp_path, &maildir_names)
+            .with_context(|| format!("failed to recover {}", tmp_path.display()))?;
+    }
+    Ok(())
+}
+
+/// Takes the lock of the packed directory, and recovers archives of all
+/// folders left by interrupted runs before anything reads or rewrites them.
+pub fn lock_and_recover(args: &Args) -> Result<PackedLock> {
+    let lock = lock::lock_packed_dir(args)?;
+    for folder in folder::list_all_folders(args)? {
+        recover_archives(args, &folder)?;
+    }
+    Ok(lock)
+}
@@ -0,0 +1,175 @@
+use crate::archive;
+use crate::args::{Args, SearchArgs};
+use crate::collect::read_headers;
+use crate::folder;
+use crate::index::{self, IndexEntry};
+use crate::mbox;
+use crate::utils::{self, get_file_name};
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::Path;
+
+/// An email matching the search.
+struct Match {
+    /// Path of the archive relative to the packed directory.
+    archive: String,
+    entry: IndexEntry,
+    /// Content of the email, which is only read when it is needed.
+    content: Vec<u8>,
+    /// The `From ` line of the email in an mbox archive.
+    from_line: Option<Vec<u8>>,
+}
+
+fn contains_ignore_case(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty()
+        || haystack
+            .windows(needle.len())
+            .any(|window| window.eq_ignore_ascii_case(needle))
+}
+
+fn matches(search_args: &SearchArgs, entry: &IndexEntry) -> bool {
+    let fields = [
+        (&entry.from, &search_args.from),
+        (&entry.to, &search_args.to),
+        (&entry.subject, &search_args.subject),
+        (&entry.message_id, &search_args.message_id),
+    ];
+    let fields_match = fields.iter().all(|(value, text)| match text {
+        Some(text) => value
+            .as_ref()
+            .is_some_and(|value| contains_ignore_case(value.as_bytes(), text.as_bytes())),
+        None => true,
+    });
+    if !fields_match {
+        return false;
+    }
+    if search_args.since.is_none() && search_args.until.is_none() {
+        return true;
+    }
+    // Emails without a date never match a date range.
+    let date = match entry.datetime() {
+        Some(date) => date.naive_utc().date(),
+        None => return false,
+    };
+    search_args.since.is_none_or(|since| date >= since)
+        && search_args.until.is_none_or(|until| date <= until)
+}
+
+/// Searches the archive. The index of the archive is used if it is valid, in
+/// which case the archive is only read to get the content of matches.
+fn search_archive(
+    packed_dir: &Path,
+    archive_path: &Path,
+    search_args: &SearchArgs,
+) -> Result<Vec<Match>> {
+    let archive = archive_path
+        .strip_prefix(packed_dir)
+        .unwrap_or(archive_path);
+    let archive = archive.to_string_lossy().into_owned();
+    let need_content = search_args.extract.is_some() || search_args.mbox;
+    let index_entries = index::read_index(archive_path);
+    let indexed = index_entries.is_some();
+    let mut result: Vec<_> = index_entries
+        .into_iter()
+        .flatten()
+        .filter(|entry| matches(search_args, entry))
+        .map(|entry| Match {
+            archive: archive.clone(),
+            entry,
+            content: vec![],
+            from_line: None,
+        })
+        .collect();
+    if indexed && (result.is_empty() || !need_content) {
+        return Ok(result);
+    }
+
+    archive::read_entries(File::open(archive_path)?, |mut entry| {
+        if indexed {
+            let name = get_file_name(&entry.path).to_string_lossy();
+            if let Some(m) = result.iter_mut().find(|m| m.entry.name == name) {
+                m.content = entry.read_content()?;
+                m.from_line = entry.from_line;
+            }
+            return Ok(());
+        }
+        let content = if need_content {
+            entry.read_content()?
+        } else {
+            vec![]
+        };
+        let digest = entry.digest()?;
+        let index_entry = IndexEntry::new(&entry.path, &digest)?;
+        if matches(search_args, &index_entry) {
+            result.push(Match {
+                archive: archive.clone(),
+                entry: index_entry,
+                content,
+                from_line: entry.from_line,
+            });
+        }
+        Ok(())
+    })?;
+    Ok(result)
+}
+
+fn extract_match(dir: &Path, m: &Match) -> Result<()> {
+    let path = dir.join(&m.entry.name);
+    match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(mut file) => file
+            .write_all(&m.content)
+            .with_context(|| format!("failed to write {}", path.display())),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+            eprintln!("Warning: {} exists, skipped", path.display());
+            Ok(())
+        }
+        Err(e) => Err(e).with_context(|| format!("failed to create {}", path.display())),
+    }
+}
+
+/// Searches all archives in parallel, and prints or extracts the matching
+/// emails in the order of archives.
+pub fn search(args: &Args, search_args: &SearchArgs) -> Result<()> {
+    let archives = folder::list_all_archives(args)?;
+    let progress = utils::create_progress_bar(args, archives.len());
+    let matches = archives
+        .par_iter()
+        .map(|path| {
+            let result = search_archive(&args.packed_dir, path, search_args)
+                .with_context(|| format!("failed to search {}", path.display()));
+            progress.inc(1);
+            result
+        })
+        .collect::<Result<Vec<_>>>()?;
+    progress.finish_and_clear();
+
+    if let Some(dir) = &search_args.extract {
+        fs::create_dir_all(dir)?;
+    }
+    let mut stdout = io::stdout().lock();
+    let mut count = 0;
+    for m in matches.iter().flatten() {
+        count += 1;
+        if search_args.mbox {
+            let from_line = match &m.from_line {
+                Some(from_line) => from_line.clone(),
+                None => {
+                    let headers = read_headers(&m.content[..])?;
+                    mbox::get_from_line(&headers, m.entry.datetime().as_ref())
+                }
+            };
+            mbox::write_message(&mut stdout, &from_line, &m.content)?;
+            continue;
+        }
+        writeln!(stdout, "{}: {}", m.archive, m.entry.name)?;
+        if let Some(dir) = &search_args.extract {
+            extract_match(dir, m)?;
+        }
+    }
+    if !args.quiet {
+        eprintln!("Found {} matching email(s)", count);
+    }
+    Ok(())
+}
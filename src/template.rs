@@ -0,0 +1,206 @@
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Datelike, NaiveDate, NaiveDateTime};
+use std::path::{Component, Path};
+use std::str::FromStr;
+
+/// A placeholder in a name template.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Placeholder {
+    /// Name of the folder, or `INBOX` for the root folder.
+    Folder,
+    /// Four-digit year.
+    Year,
+    /// Quarter of the year, from 1 to 4.
+    Quarter,
+    /// Two-digit month.
+    Month,
+    /// Two-digit day of the month.
+    Day,
+    /// Four-digit ISO 8601 week-numbering year.
+    WeekYear,
+    /// Two-digit ISO 8601 week number.
+    Week,
+}
+
+impl Placeholder {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "folder" => Placeholder::Folder,
+            "year" => Placeholder::Year,
+            "quarter" => Placeholder::Quarter,
+            "month" => Placeholder::Month,
+            "day" => Placeholder::Day,
+            "week_year" => Placeholder::WeekYear,
+            "week" => Placeholder::Week,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    /// Literal text, which may contain strftime-like specifiers.
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// Template of archive names, e.g. `{year}/{month}` or `{folder}-%Y-%m`.
+/// Besides placeholders in braces, literal text can contain strftime-like
+/// specifiers as supported by `chrono::format::strftime`. `/` in the
+/// expanded name creates nested directories.
+#[derive(Clone, Debug)]
+pub struct NameTemplate {
+    segments: Vec<Segment>,
+}
+
+impl NameTemplate {
+    /// Whether the template needs a date to expand.
+    pub fn uses_date(&self) -> bool {
+        self.segments.iter().any(|segment| match segment {
+            Segment::Literal(s) => s.contains('%'),
+            Segment::Placeholder(p) => *p != Placeholder::Folder,
+        })
+    }
+
+    /// Expands the template with the given date and folder name. The date
+    /// must be given if the template uses date.
+    pub fn expand(&self, dt: Option<&NaiveDateTime>, folder: &str) -> String {
+        let mut result = String::new();
+        for segment in &self.segments {
+            let dt = || dt.expect("Date is required for the template");
+            match segment {
+                Segment::Literal(s) if s.contains('%') => {
+                    result.push_str(&dt().format(s).to_string())
+                }
+                Segment::Literal(s) => result.push_str(s),
+                Segment::Placeholder(Placeholder::Folder) if folder.is_empty() => {
+                    result.push_str("INBOX")
+                }
+                Segment::Placeholder(Placeholder::Folder) => result.push_str(folder),
+                Segment::Placeholder(Placeholder::Year) => {
+                    result.push_str(&dt().format("%Y").to_string())
+                }
+                Segment::Placeholder(Placeholder::Quarter) => {
+                    result.push_str(&(dt().month0() / 3 + 1).to_string())
+                }
+                Segment::Placeholder(Placeholder::Month) => {
+                    result.push_str(&dt().format("%m").to_string())
+                }
+                Segment::Placeholder(Placeholder::Day) => {
+                    result.push_str(&dt().format("%d").to_string())
+                }
+                Segment::Placeholder(Placeholder::WeekYear) => {
+                    result.push_str(&dt().format("%G").to_string())
+                }
+                Segment::Placeholder(Placeholder::Week) => {
+                    result.push_str(&dt().format("%V").to_string())
+                }
+            }
+        }
+        result
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segments = vec![];
+        let mut rest = s;
+        while !rest.is_empty() {
+            let (literal, placeholder) = match rest.find('{') {
+                Some(start) => {
+                    let end = rest[start..]
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed placeholder in {:?}", s))?;
+                    let name = &rest[start + 1..start + end];
+                    let placeholder = Placeholder::from_name(name)
+                        .ok_or_else(|| format!("unknown placeholder {{{}}}", name))?;
+                    let literal = &rest[..start];
+                    rest = &rest[start + end + 1..];
+                    (literal, Some(placeholder))
+                }
+                None => (std::mem::take(&mut rest), None),
+            };
+            if StrftimeItems::new(literal).any(|item| item == Item::Error) {
+                return Err(format!("invalid format specifier in {:?}", literal));
+            }
+            if !literal.is_empty() {
+                segments.push(Segment::Literal(literal.to_string()));
+            }
+            segments.extend(placeholder.map(Segment::Placeholder));
+        }
+
+        // Check that the template always expands to a relative path inside
+        // the packed directory.
+        let template = NameTemplate { segments };
+        let sample = NaiveDate::from_ymd_opt(2017, 6, 30)
+            .unwrap()
+            .and_hms_opt(20, 0, 0)
+            .unwrap();
+        let name = template.expand(Some(&sample), "folder");
+        let valid = !name.is_empty()
+            && !name.ends_with('/')
+            && !name.contains("//")
+            && Path::new(&name)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)));
+        if !valid {
+            return Err(format!("{:?} is not a valid relative path", s));
+        }
+        Ok(template)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let dt = NaiveDate::from_ymd_opt(2017, 6, 30)
+            .unwrap()
+            .and_hms_opt(20, 0, 0)
+            .unwrap();
+        let expand = |template: &str, folder: &str| {
+            let template: NameTemplate = template.parse().unwrap();
+            template.expand(Some(&dt), folder)
+        };
+
+        assert_eq!(expand("{year}/{month}", ""), "2017/06");
+        assert_eq!(
+            expand("{folder}-{year}-{month}", "Lists.rust"),
+            "Lists.rust-2017-06"
+        );
+        assert_eq!(expand("{folder}-{year}-{month}", ""), "INBOX-2017-06");
+        assert_eq!(expand("{year}-Q{quarter}", ""), "2017-Q2");
+        assert_eq!(expand("{week_year}-W{week}", ""), "2017-W26");
+        assert_eq!(expand("%Y/%b-{day}", ""), "2017/Jun-30");
+        assert_eq!(expand("archive", ""), "archive");
+    }
+
+    #[test]
+    fn test_uses_date() {
+        let uses_date = |template: &str| template.parse::<NameTemplate>().unwrap().uses_date();
+        assert!(uses_date("{year}"));
+        assert!(uses_date("%Y"));
+        assert!(!uses_date("unknown"));
+        assert!(!uses_date("{folder}/unknown"));
+    }
+
+    #[test]
+    fn test_invalid() {
+        for template in &[
+            "",
+            "{year",
+            "{yaer}",
+            "%Q",
+            "/{year}",
+            "{year}/",
+            "../{year}",
+            "{year}//{month}",
+        ] {
+            assert!(template.parse::<NameTemplate>().is_err(), "{:?}", template);
+        }
+    }
+}
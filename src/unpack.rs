@@ -0,0 +1,138 @@
+use crate::archive;
+use crate::args::{Args, UnpackArgs};
+use crate::compact;
+use crate::execute::get_unique_name;
+use crate::folder;
+use crate::recover;
+use crate::utils::{self, get_file_name, matches_wildcard};
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::path::Path;
+
+/// Lists unique names of emails in new and cur of the maildir.
+fn list_unique_names(maildir: &Path) -> Result<HashSet<OsString>> {
+    let mut result = HashSet::new();
+    for dir in &["new", "cur"] {
+        let entries = match fs::read_dir(maildir.join(dir)) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            result.insert(get_unique_name(&entry?.file_name()).to_os_string());
+        }
+    }
+    Ok(result)
+}
+
+/// Restores matching entries of the archive into the maildir, and returns
+/// names of the restored entries. Emails are written into maildir/tmp first
+/// and then linked into place, which never overwrites an existing file.
+/// https://cr.yp.to/proto/maildir.html
+fn restore_entries(
+    archive_path: &Path,
+    unpack_args: &UnpackArgs,
+    maildir: &Path,
+    existing: &mut HashSet<OsString>,
+) -> Result<HashSet<OsString>> {
+    // Emails in maildir/cur need the info suffix, which has no flag here.
+    let (dir, info) = if unpack_args.cur {
+        ("cur", ":2,")
+    } else {
+        ("new", "")
+    };
+    let mut restored = HashSet::new();
+    archive::read_entries(File::open(archive_path)?, |mut entry| {
+        let file_name = get_file_name(&entry.path).to_os_string();
+        let name = file_name.to_string_lossy();
+        if !unpack_args.entries.is_empty()
+            && !unpack_args
+                .entries
+                .iter()
+                .any(|pattern| matches_wildcard(pattern, &name))
+        {
+            return Ok(());
+        }
+        if existing.contains(&file_name) {
+            eprintln!("Warning: {:?} exists in the maildir, skipped", file_name);
+            return Ok(());
+        }
+
+        let tmp_path = maildir.join("tmp").join(&file_name);
+        let mut tmp_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .with_context(|| format!("failed to create {}", tmp_path.display()))?;
+        let mut dest_name = file_name.clone();
+        dest_name.push(info);
+        let dest_path = maildir.join(dir).join(dest_name);
+        let result = io::copy(&mut entry, &mut tmp_file)
+            .and_then(|_| tmp_file.sync_all())
+            .and_then(|_| fs::hard_link(&tmp_path, &dest_path));
+        fs::remove_file(&tmp_path)?;
+        match result {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                eprintln!("Warning: {:?} exists in the maildir, skipped", file_name);
+                return Ok(());
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to restore {:?}", file_name));
+            }
+        }
+        existing.insert(file_name.clone());
+        restored.insert(file_name);
+        Ok(())
+    })?;
+    Ok(restored)
+}
+
+/// Restores emails from the selected archives into a maildir.
+pub fn unpack(args: &Args, unpack_args: &UnpackArgs) -> Result<()> {
+    // The lock is needed for removing emails from the archives, as well as
+    // for not racing with a packing run when restoring into the maildir.
+    let _lock = recover::lock_and_recover(args)?;
+    let archives = folder::select_archives(args, &unpack_args.archives)?;
+    let maildir = unpack_args.to.as_deref().unwrap_or(&args.maildir);
+    for dir in &["tmp", "new", "cur"] {
+        fs::create_dir_all(maildir.join(dir))?;
+    }
+
+    let mut existing = list_unique_names(maildir)?;
+    let mut total = 0;
+    for archive_path in archives {
+        let name = archive_path.strip_prefix(&args.packed_dir).unwrap();
+        let restored = restore_entries(&archive_path, unpack_args, maildir, &mut existing)
+            .with_context(|| format!("failed to unpack {}", name.display()))?;
+        if restored.is_empty() {
+            continue;
+        }
+        utils::sync_dir(&maildir.join(if unpack_args.cur { "cur" } else { "new" }))?;
+        if !args.quiet {
+            eprintln!(
+                "Restored {} email(s) from {}",
+                restored.len(),
+                name.display()
+            );
+        }
+        total += restored.len();
+        if unpack_args.remove {
+            let level = unpack_args.level;
+            compact::rewrite_archive(
+                &archive_path,
+                &restored,
+                level,
+                Some(&unpack_args.threading),
+            )
+            .with_context(|| format!("failed to remove emails from {}", name.display()))?;
+        }
+    }
+    if !args.quiet {
+        eprintln!("Restored {} email(s) in total", total);
+    }
+    Ok(())
+}
@@ -1,5 +1,9 @@
-use crate::args::Args;
+use crate::args::{Args, ThreadArgs};
+use anyhow::Result;
 use indicatif::ProgressBar;
+use std::ffi::OsStr;
+use std::io;
+use std::path::Path;
 
 pub fn create_progress_bar(args: &Args, len: usize) -> ProgressBar {
     if args.quiet {
@@ -8,3 +12,90 @@ pub fn create_progress_bar(args: &Args, len: usize) -> ProgressBar {
         ProgressBar::new(len as u64)
     }
 }
+
+/// Runs the function, which packs archives in parallel, on a single thread
+/// if each xz archive is compressed with several threads, so that the
+/// machine isn't oversubscribed.
+pub fn install_packing_pool<R: Send>(
+    threading: &ThreadArgs,
+    f: impl FnOnce() -> R + Send,
+) -> Result<R> {
+    if threading.threads == 1 {
+        return Ok(f());
+    }
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build()?;
+    Ok(pool.install(f))
+}
+
+pub fn get_file_name(path: &Path) -> &OsStr {
+    path.file_name().expect("Unexpected path")
+}
+
+/// Flushes entries of the directory to disk, so that files renamed into it
+/// or removed from it stay so after a crash.
+#[cfg(unix)]
+pub fn sync_dir(path: &Path) -> io::Result<()> {
+    std::fs::File::open(path)?.sync_all()
+}
+
+/// Directories can't be opened as files on other platforms, so there is
+/// nothing to flush.
+#[cfg(not(unix))]
+pub fn sync_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Matches the name against a wildcard pattern, where `*` matches any
+/// sequence of characters and `?` matches a single character.
+pub fn matches_wildcard(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<_> = pattern.chars().collect();
+    let name: Vec<_> = name.chars().collect();
+    // Position after the last `*` in the pattern and the position in the
+    // name it is matched up to, for backtracking.
+    let mut star = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches_wildcard() {
+        assert!(matches_wildcard("abc", "abc"));
+        assert!(!matches_wildcard("abc", "abcd"));
+        assert!(!matches_wildcard("abcd", "abc"));
+        assert!(matches_wildcard("*", ""));
+        assert!(matches_wildcard("*", "anything"));
+        assert!(matches_wildcard("a?c", "abc"));
+        assert!(!matches_wildcard("a?c", "ac"));
+        assert!(matches_wildcard(
+            "1538*.host",
+            "1538824850.M951087P44546.host"
+        ));
+        assert!(matches_wildcard("*a*b", "xaxab"));
+        assert!(!matches_wildcard("*a*b", "xaxabc"));
+        assert!(matches_wildcard("**", "ab"));
+    }
+}
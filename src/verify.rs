@@ -1,4 +1,5 @@
 use sha2::{Digest, Sha512};
+use std::fs::File;
 use std::io::{self, Read};
 
 pub const HASH_LEN: usize = 64;
@@ -17,9 +18,14 @@ impl<R: Read> StreamHasher<R> {
         }
     }
 
-    pub fn get_result(self) -> HashResult {
+    pub fn into_inner(self) -> R {
+        self.input
+    }
+
+    /// Returns the hash of what has been read, and starts over.
+    pub fn get_result(&mut self) -> HashResult {
         let mut result = [0; HASH_LEN];
-        result.copy_from_slice(self.hasher.finalize().as_slice());
+        result.copy_from_slice(self.hasher.finalize_reset().as_slice());
         result
     }
 }
@@ -31,3 +37,25 @@ impl<R: Read> Read for StreamHasher<R> {
         Ok(size)
     }
 }
+
+pub fn hash_file(file: File) -> HashResult {
+    let mut hasher = StreamHasher::new(file);
+    let mut buf = [0; 4096];
+    while let Ok(size) = hasher.read(&mut buf) {
+        if size == 0 {
+            break;
+        }
+    }
+    hasher.get_result()
+}
+
+pub fn hash_bytes(data: &[u8]) -> HashResult {
+    let mut result = [0; HASH_LEN];
+    result.copy_from_slice(Sha512::digest(data).as_slice());
+    result
+}
+
+/// Formats the hash in lowercase hexadecimal.
+pub fn to_hex(hash: &HashResult) -> String {
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
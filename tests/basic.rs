@@ -1,7 +1,11 @@
+use assert_cmd::assert::Assert;
 use assert_cmd::prelude::*;
+use bzip2::read::MultiBzDecoder;
+use flate2::read::MultiGzDecoder;
+use fs2::FileExt;
 use leak::Leak;
 use once_cell::sync::Lazy;
-use sha2::{Digest, Sha256};
+use sha2::{Digest, Sha256, Sha512};
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::ffi::OsStr;
@@ -12,14 +16,28 @@ use std::ops::Deref;
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime};
 use tar::Archive as TarArchive;
 use tempfile::TempDir;
 use xz2::read::XzDecoder;
 
 type HashResult = [u8; 32];
 
-const ARCHIVE_SUFFIX: &'static str = ".tar.xz";
-const BACKUP_SUFFIX: &'static str = ".tar.xz.bak";
+const ARCHIVE_SUFFIX: &str = ".tar.xz";
+
+type OpenArchive = fn(File) -> io::Result<Box<dyn Read>>;
+
+/// Suffixes of archives in each format with functions to decompress them.
+const ARCHIVE_FORMATS: &[(&str, OpenArchive)] = &[
+    (".tar.xz", |file| {
+        Ok(Box::new(XzDecoder::new_multi_decoder(file)))
+    }),
+    (".tar.zst", |file| Ok(Box::new(zstd::Decoder::new(file)?))),
+    (".tar.gz", |file| Ok(Box::new(MultiGzDecoder::new(file)))),
+    (".tar.bz2", |file| Ok(Box::new(MultiBzDecoder::new(file)))),
+    (".tar", |file| Ok(Box::new(file))),
+];
 
 static KEEP_TEST_DIR: Lazy<bool> = Lazy::new(|| env::var("KEEP_TEST_DIR").is_ok());
 static EMAILS_PATH: Lazy<PathBuf> = Lazy::new(|| {
@@ -93,6 +111,7 @@ struct TempMaildir {
     name: &'static str,
     tmp_dir: Option<TempDir>,
     new_dir: PathBuf,
+    cur_dir: PathBuf,
     packed_dir: PathBuf,
 }
 
@@ -100,15 +119,16 @@ impl TempMaildir {
     fn new(name: &'static str) -> io::Result<Self> {
         let tmp_dir = TempDir::new()?;
         let new_dir = tmp_dir.path().join("new");
+        let cur_dir = tmp_dir.path().join("cur");
         let packed_dir = tmp_dir.path().join("packed");
-        // Create maildir structure. Since we currently only use the new
-        // directory, we only create it. If we extend support to cur at
-        // some point, we should create that as well.
+        // Create maildir structure.
         fs::create_dir(&new_dir)?;
+        fs::create_dir(&cur_dir)?;
         Ok(TempMaildir {
             name,
             tmp_dir: Some(tmp_dir),
             new_dir,
+            cur_dir,
             packed_dir,
         })
     }
@@ -118,20 +138,50 @@ impl TempMaildir {
     }
 
     fn fill_maildir(&self, emails: impl Iterator<Item = impl AsRef<Path>>) -> io::Result<()> {
+        fill_dir(&self.new_dir, emails)
+    }
+
+    /// Create a Maildir++ subfolder with the given name, and return the
+    /// path to its new directory.
+    fn create_folder(&self, name: &str) -> io::Result<PathBuf> {
+        let folder = self.path().join(format!(".{}", name));
+        for dir in &["new", "cur", "tmp"] {
+            fs::create_dir_all(folder.join(dir))?;
+        }
+        Ok(folder.join("new"))
+    }
+
+    /// Fill maildir/cur with the given emails, with the given info suffix
+    /// appended to their file names.
+    fn fill_maildir_cur(
+        &self,
+        emails: impl Iterator<Item = impl AsRef<Path>>,
+        info: &str,
+    ) -> io::Result<()> {
         for email in emails {
             let email = email.as_ref();
-            fs::copy(email, self.new_dir.join(email.file_name().unwrap()))?;
+            let mut file_name = email.file_name().unwrap().to_os_string();
+            file_name.push(info);
+            fs::copy(email, self.cur_dir.join(file_name))?;
         }
         Ok(())
     }
 
     fn execute_packing(&self) {
+        self.execute_packing_with(&[]);
+    }
+
+    fn execute_packing_with(&self, args: &[&str]) {
+        self.execute_packing_assert(args).success();
+    }
+
+    fn execute_packing_assert(&self, args: &[&str]) -> Assert {
         Command::cargo_bin(env!("CARGO_PKG_NAME"))
             .unwrap()
             .arg("--quiet")
+            .args(args)
             .arg(self.path())
             .assert()
-            .success();
     }
 }
 
@@ -147,6 +197,14 @@ impl Drop for TempMaildir {
     }
 }
 
+fn fill_dir(dir: &Path, emails: impl Iterator<Item = impl AsRef<Path>>) -> io::Result<()> {
+    for email in emails {
+        let email = email.as_ref();
+        fs::copy(email, dir.join(email.file_name().unwrap()))?;
+    }
+    Ok(())
+}
+
 fn generate_email_set(
     iter: impl Iterator<Item = impl Deref<Target = &'static Path>>,
 ) -> HashSet<&'static Path> {
@@ -190,24 +248,35 @@ fn join_names<'a>(mut iter: impl Iterator<Item = &'a str>) -> String {
 }
 
 fn get_name_with_suffix<'a>(file_name: &'a str, suffix: &str) -> Option<&'a str> {
-    if file_name.ends_with(suffix) {
-        Some(&file_name[..file_name.len() - suffix.len()])
-    } else {
-        None
-    }
+    file_name.strip_suffix(suffix)
 }
 
 fn check_packed(
     maildir: &TempMaildir,
+    expected: HashMap<&str, HashMap<&OsStr, HashResult>>,
+    expected_backup: HashMap<&str, HashResult>,
+) -> io::Result<()> {
+    check_packed_dir(&maildir.packed_dir, &[], expected, expected_backup)
+}
+
+fn check_packed_dir(
+    packed_dir: &Path,
+    subdirs: &[&str],
     mut expected: HashMap<&str, HashMap<&OsStr, HashResult>>,
     mut expected_backup: HashMap<&str, HashResult>,
 ) -> io::Result<()> {
-    for archive in fs::read_dir(&maildir.packed_dir)? {
+    for archive in fs::read_dir(packed_dir)? {
         let archive = archive?.path();
         let archive_name = archive.file_name().unwrap().to_str().unwrap();
+        if archive.is_dir() && subdirs.contains(&archive_name) {
+            continue;
+        }
         let report_unexpected_file =
             || -> ! { panic!("Unexpected file {} in maildir/packed", archive_name) };
-        if let Some(key) = get_name_with_suffix(archive_name, ARCHIVE_SUFFIX) {
+        let format = ARCHIVE_FORMATS.iter().find_map(|&(suffix, open)| {
+            get_name_with_suffix(archive_name, suffix).map(|key| (key, open))
+        });
+        if let Some((key, open_archive)) = format {
             // Retrieve the expected content of the archive.
             let mut expected_content = match expected.remove(key) {
                 Some(content) => content,
@@ -222,8 +291,9 @@ fn check_packed(
                 "Archive file should use mode 0o600"
             );
             // Read the archive and check the content.
-            let xz_reader = XzDecoder::new(file);
-            let mut tar_archive = TarArchive::new(xz_reader);
+            let mut tar_archive = TarArchive::new(open_archive(file)?);
+            // Appended archives have end-of-archive blocks between streams.
+            tar_archive.set_ignore_zeros(true);
             for entry in tar_archive.entries()? {
                 let entry = entry?;
                 let file_name = entry.header().path()?.into_owned();
@@ -245,13 +315,25 @@ fn check_packed(
                 );
             }
             // Check that no file left.
-            if expected_content.len() > 0 {
+            if !expected_content.is_empty() {
                 let files = join_names(expected_content.keys().map(|name| name.to_str().unwrap()));
                 panic!("Files not found in archive {}: {}", archive_name, files);
             }
             continue;
         }
-        if let Some(key) = get_name_with_suffix(archive_name, BACKUP_SUFFIX) {
+        // Indexes are fine as long as their archives exist.
+        if let Some(name) = get_name_with_suffix(archive_name, ".idx") {
+            if archive.with_file_name(name).is_file() {
+                continue;
+            }
+            report_unexpected_file();
+        }
+        let backup_key = get_name_with_suffix(archive_name, ".bak").and_then(|name| {
+            ARCHIVE_FORMATS
+                .iter()
+                .find_map(|&(suffix, _)| get_name_with_suffix(name, suffix))
+        });
+        if let Some(key) = backup_key {
             let expected_hash = match expected_backup.remove(key) {
                 Some(hash) => hash,
                 None => report_unexpected_file(),
@@ -267,13 +349,13 @@ fn check_packed(
         report_unexpected_file();
     }
     // Check that all archives are created.
-    if expected.len() > 0 {
-        let archives = join_names(expected.keys().map(|&name| name));
+    if !expected.is_empty() {
+        let archives = join_names(expected.keys().copied());
         panic!("Archives not found in maildir/packed: {}", archives);
     }
     // Check that all backup are created.
-    if expected_backup.len() > 0 {
-        let backups = join_names(expected_backup.keys().map(|&name| name));
+    if !expected_backup.is_empty() {
+        let backups = join_names(expected_backup.keys().copied());
         panic!("Backups not found in maildir/packed: {}", backups);
     }
     Ok(())
@@ -285,6 +367,11 @@ fn check_empty_maildir(maildir: &TempMaildir) -> io::Result<()> {
         0,
         "Unexpected file in maildir/new"
     );
+    assert_eq!(
+        maildir.cur_dir.read_dir()?.count(),
+        0,
+        "Unexpected file in maildir/cur"
+    );
     Ok(())
 }
 
@@ -303,6 +390,17 @@ fn basic_packing() -> io::Result<()> {
     check_empty_maildir(&maildir)
 }
 
+#[test]
+fn no_sync_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("no_sync_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_with(&["--no-sync"]);
+    let expected = generate_expected_result(&emails);
+    check_packed(&maildir, expected, HashMap::new())?;
+    check_empty_maildir(&maildir)
+}
+
 #[test]
 fn incremental_packing() -> io::Result<()> {
     let maildir = TempMaildir::new("incremental_packing")?;
@@ -348,8 +446,1472 @@ fn incremental_packing() -> io::Result<()> {
     /* Second packing */
     maildir.fill_maildir(second_set.iter())?;
     maildir.execute_packing();
-    let merged = second_set.union(&initial_set).map(|&email| email).collect();
+    let merged = second_set.union(&initial_set).copied().collect();
     let expected = generate_expected_result(&merged);
     check_packed(&maildir, expected, expected_backup)?;
     check_empty_maildir(&maildir)
 }
+
+#[test]
+fn append_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("append_packing")?;
+    let archives: Vec<_> = ALL_EMAILS
+        .iter()
+        .filter(|&(_, emails)| emails.len() >= 2)
+        .collect();
+    let initial_set = generate_email_set(
+        archives
+            .iter()
+            .flat_map(|&(_, emails)| emails[..emails.len() * 2 / 3].iter()),
+    );
+    let second_set = generate_email_set(
+        archives
+            .iter()
+            .flat_map(|&(_, emails)| emails[emails.len() / 3..].iter()),
+    );
+    let merged: HashSet<_> = second_set.union(&initial_set).copied().collect();
+    let read_archives = || {
+        archives
+            .iter()
+            .map(|&(&archive, _)| {
+                let file_name = format!("{}{}", archive, ARCHIVE_SUFFIX);
+                Ok((archive, fs::read(maildir.packed_dir.join(file_name))?))
+            })
+            .collect::<io::Result<HashMap<_, _>>>()
+    };
+
+    /* Initial packing */
+    maildir.fill_maildir(initial_set.iter())?;
+    maildir.execute_packing_with(&["--append"]);
+    check_packed(
+        &maildir,
+        generate_expected_result(&initial_set),
+        HashMap::new(),
+    )?;
+    let initial_archives = read_archives()?;
+
+    /* Appending keeps the existing content and makes no backup */
+    maildir.fill_maildir(second_set.iter())?;
+    maildir.execute_packing_with(&["--append", "--verify"]);
+    check_packed(&maildir, generate_expected_result(&merged), HashMap::new())?;
+    check_empty_maildir(&maildir)?;
+    let appended_archives = read_archives()?;
+    for (archive, content) in &appended_archives {
+        let initial_content = &initial_archives[archive];
+        assert!(content.len() > initial_content.len());
+        assert!(content.starts_with(initial_content));
+    }
+
+    /* An interrupted append is rolled back */
+    let (&archive, content) = appended_archives.iter().next().unwrap();
+    let archive_path = maildir
+        .packed_dir
+        .join(format!("{}{}", archive, ARCHIVE_SUFFIX));
+    let journal_path = archive_path.with_extension("xz.append");
+    fs::write(&journal_path, content.len().to_string())?;
+    let mut broken = content.clone();
+    broken.extend_from_slice(b"\xfd7zXZ\x00 broken stream");
+    fs::write(&archive_path, &broken)?;
+    maildir.execute_packing();
+    assert_eq!(&fs::read(&archive_path)?, content);
+    assert!(!journal_path.exists());
+
+    /* Compacting rolls back an interrupted append first */
+    fs::write(&journal_path, content.len().to_string())?;
+    fs::write(&archive_path, &broken)?;
+
+    /* Compacting merges the streams into one */
+    Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("compact")
+        .arg(maildir.path())
+        .assert()
+        .success();
+    assert!(!journal_path.exists());
+    let expected_backup = appended_archives
+        .iter()
+        .map(|(&archive, content)| Ok((archive, hash_content(&content[..])?)))
+        .collect::<io::Result<_>>()?;
+    check_packed(&maildir, generate_expected_result(&merged), expected_backup)?;
+    for (archive, content) in read_archives()? {
+        assert!(content.len() < appended_archives[archive].len());
+    }
+    Ok(())
+}
+
+#[test]
+fn cur_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("cur_packing")?;
+    // Put half of the emails in the new dir, and the rest in the cur dir.
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    let (new_set, cur_set): (Vec<_>, Vec<_>) =
+        emails.iter().enumerate().partition(|(i, _)| i % 2 == 0);
+    maildir.fill_maildir(new_set.into_iter().map(|(_, email)| email))?;
+    maildir.fill_maildir_cur(cur_set.into_iter().map(|(_, email)| email), ":2,S")?;
+    // Pack the maildir.
+    maildir.execute_packing_with(&["--include-cur"]);
+    // Check the result.
+    let expected = generate_expected_result(&emails);
+    check_packed(&maildir, expected, HashMap::new())?;
+    check_empty_maildir(&maildir)
+}
+
+#[test]
+fn cur_not_included_by_default() -> io::Result<()> {
+    let maildir = TempMaildir::new("cur_not_included_by_default")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir_cur(emails.iter(), ":2,S")?;
+    maildir.execute_packing();
+    assert!(!maildir.packed_dir.exists());
+    assert_eq!(maildir.cur_dir.read_dir()?.count(), emails.len());
+    Ok(())
+}
+
+#[test]
+fn moved_email_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("moved_email_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+
+    /* Initial packing from the new dir */
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_with(&["--include-cur"]);
+    let expected = generate_expected_result(&emails);
+    check_packed(&maildir, expected, HashMap::new())?;
+    check_empty_maildir(&maildir)?;
+
+    /* The same emails show up again in the cur dir with flags */
+    let snapshot = snapshot_dir(&maildir.packed_dir)?;
+    maildir.fill_maildir_cur(emails.iter(), ":2,RS")?;
+    maildir.execute_packing_with(&["--include-cur"]);
+    // Nothing is added, so archives are untouched.
+    assert_eq!(snapshot_dir(&maildir.packed_dir)?, snapshot);
+    check_empty_maildir(&maildir)
+}
+
+#[test]
+fn recursive_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("recursive_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    let (root_set, folder_set): (HashSet<_>, HashSet<_>) =
+        emails.iter().enumerate().partition(|(i, _)| i % 2 == 0);
+    let root_set: HashSet<_> = root_set.into_iter().map(|(_, &email)| email).collect();
+    let folder_set: HashSet<_> = folder_set.into_iter().map(|(_, &email)| email).collect();
+    maildir.fill_maildir(root_set.iter())?;
+    let folder_new = maildir.create_folder("Lists.rust")?;
+    fill_dir(&folder_new, folder_set.iter())?;
+    // An empty folder shouldn't produce anything.
+    maildir.create_folder("Trash")?;
+    // Neither should a folder with only one of new and cur.
+    fs::create_dir_all(maildir.path().join(".Drafts").join("cur"))?;
+
+    maildir.execute_packing_with(&["--recursive"]);
+    let expected = generate_expected_result(&root_set);
+    check_packed_dir(
+        &maildir.packed_dir,
+        &["Lists.rust"],
+        expected,
+        HashMap::new(),
+    )?;
+    let expected = generate_expected_result(&folder_set);
+    let folder_packed = maildir.packed_dir.join("Lists.rust");
+    check_packed_dir(&folder_packed, &[], expected, HashMap::new())?;
+    check_empty_maildir(&maildir)?;
+    assert_eq!(folder_new.read_dir()?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn received_fallback_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("received_fallback_packing")?;
+    // This email has no Date header, and its top-most Received header is
+    // dated Fri, 10 Dec 2010 19:20:19 -0800.
+    let email = ALL_EMAILS["unknown"]
+        .iter()
+        .copied()
+        .find(|email| email.ends_with("Ld4vZTKTYhC0aqQcgnDpRgPQj6jBWnmS9NgUiFatY9s"))
+        .unwrap();
+    maildir.fill_maildir([email].iter())?;
+    maildir.execute_packing_with(&["--date-sources", "date,received"]);
+    let mut expected = HashMap::new();
+    let content = HashMap::from([(email.file_name().unwrap(), EMAIL_HASHS[email])]);
+    expected.insert("2010-12", content);
+    check_packed(&maildir, expected, HashMap::new())?;
+    check_empty_maildir(&maildir)
+}
+
+#[test]
+fn file_name_date_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("file_name_date_packing")?;
+    // All emails in "others" have a maildir file name delivered in 2018-10,
+    // while they are dated differently in their Date headers.
+    let emails = generate_email_set(
+        ALL_EMAILS
+            .values()
+            .flat_map(|l| l.iter())
+            .filter(|email| email.components().any(|c| c.as_os_str() == "others")),
+    );
+    assert!(!emails.is_empty());
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_with(&["--force-date-source", "file-name"]);
+    let content = emails
+        .iter()
+        .map(|&email| (email.file_name().unwrap(), EMAIL_HASHS[email]))
+        .collect();
+    let expected = HashMap::from([("2018-10", content)]);
+    check_packed(&maildir, expected, HashMap::new())?;
+    check_empty_maildir(&maildir)
+}
+
+#[test]
+fn mtime_fallback_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("mtime_fallback_packing")?;
+    let emails = generate_email_set(ALL_EMAILS["unknown"].iter());
+    maildir.fill_maildir(emails.iter())?;
+    // 2001-02-03T04:05:06Z
+    let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(981173106);
+    for entry in maildir.new_dir.read_dir()? {
+        File::options()
+            .write(true)
+            .open(entry?.path())?
+            .set_modified(mtime)?;
+    }
+    maildir.execute_packing_with(&["--date-sources", "date,mtime"]);
+    let mut expected = generate_expected_result(&emails);
+    let content = expected.remove("unknown").unwrap();
+    expected.insert("2001-02", content);
+    check_packed(&maildir, expected, HashMap::new())?;
+    check_empty_maildir(&maildir)?;
+
+    /* Emails unpacked for re-bucketing have lost their mtime, and are
+     * removed after packing even with --keep */
+    maildir.execute_packing_with(&["--date-sources", "date,mtime", "--rebucket", "--keep"]);
+    check_packed(&maildir, generate_expected_result(&emails), HashMap::new())?;
+    assert!(!maildir.packed_dir.join(".staging").exists());
+    check_empty_maildir(&maildir)
+}
+
+#[test]
+fn rebucket_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("rebucket_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    let (initial_set, second_set): (HashSet<_>, HashSet<_>) = emails
+        .iter()
+        .partition(|email| email.to_str().unwrap().len() % 2 == 0);
+
+    /* Initial packing monthly */
+    maildir.fill_maildir(initial_set.iter())?;
+    maildir.execute_packing();
+    let expected = generate_expected_result(&initial_set);
+    check_packed(&maildir, expected, HashMap::new())?;
+
+    /* Repacking yearly with new emails */
+    maildir.fill_maildir(second_set.iter())?;
+    maildir.execute_packing_with(&["--granularity", "yearly", "--rebucket"]);
+    let mut expected: HashMap<_, HashMap<_, _>> = HashMap::new();
+    for (archive, content) in generate_expected_result(&emails) {
+        let year = archive.split('-').next().unwrap();
+        expected.entry(year).or_default().extend(content);
+    }
+    check_packed(&maildir, expected, HashMap::new())?;
+    check_empty_maildir(&maildir)
+}
+
+#[test]
+fn name_template_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("name_template_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_with(&[
+        "--name-template",
+        "{folder}-{year}-{month}",
+        "--unknown-name",
+        "{folder}-undated",
+    ]);
+    let expected = generate_expected_result(&emails)
+        .into_iter()
+        .map(|(archive, content)| {
+            let archive = match archive {
+                "unknown" => "INBOX-undated".to_string(),
+                archive => format!("INBOX-{}", archive),
+            };
+            let archive: &str = archive.into_boxed_str().leak();
+            (archive, content)
+        })
+        .collect();
+    check_packed(&maildir, expected, HashMap::new())?;
+    check_empty_maildir(&maildir)
+}
+
+#[test]
+fn nested_name_template_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("nested_name_template_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_with(&["--name-template", "{year}/%m"]);
+    // Group expected archives by year.
+    let mut expected_unknown = HashMap::new();
+    let mut expected_years: HashMap<_, HashMap<_, _>> = HashMap::new();
+    for (archive, content) in generate_expected_result(&emails) {
+        match archive.split_once('-') {
+            Some((year, month)) => {
+                expected_years
+                    .entry(year)
+                    .or_default()
+                    .insert(month, content);
+            }
+            None => {
+                expected_unknown.insert(archive, content);
+            }
+        }
+    }
+    let years: Vec<_> = expected_years.keys().copied().collect();
+    check_packed_dir(
+        &maildir.packed_dir,
+        &years,
+        expected_unknown,
+        HashMap::new(),
+    )?;
+    for (year, expected) in expected_years {
+        check_packed_dir(
+            &maildir.packed_dir.join(year),
+            &[],
+            expected,
+            HashMap::new(),
+        )?;
+    }
+    check_empty_maildir(&maildir)
+}
+
+#[test]
+fn threaded_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("threaded_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir(emails.iter())?;
+    // Small blocks make archives consist of multiple blocks.
+    maildir.execute_packing_with(&["--threads", "4", "--block-size", "16K", "--level", "1"]);
+    let expected = generate_expected_result(&emails);
+    check_packed(&maildir, expected, HashMap::new())?;
+    check_empty_maildir(&maildir)
+}
+
+#[test]
+fn compression_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("compression_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    let initial_set: HashSet<_> = emails
+        .iter()
+        .copied()
+        .filter(|email| email.to_str().unwrap().len() % 2 == 0)
+        .collect();
+
+    /* Initial packing with xz */
+    maildir.fill_maildir(initial_set.iter())?;
+    maildir.execute_packing();
+    let expected_backup = generate_expected_result(&initial_set)
+        .keys()
+        .map(|&archive| {
+            let file_name = format!("{}{}", archive, ARCHIVE_SUFFIX);
+            let file = File::open(maildir.packed_dir.join(file_name))?;
+            Ok((archive, hash_content(file)?))
+        })
+        .collect::<io::Result<_>>()?;
+
+    /* Incremental packing with zstd converts existing archives */
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_with(&["--compression", "zstd", "--level", "3"]);
+    let expected = generate_expected_result(&emails);
+    check_packed(&maildir, expected, expected_backup)?;
+    check_empty_maildir(&maildir)?;
+    for archive in fs::read_dir(&maildir.packed_dir)? {
+        let archive = archive?.path();
+        let archive_name = archive.file_name().unwrap().to_str().unwrap();
+        assert!(
+            archive_name.ends_with(".tar.zst")
+                || archive_name.ends_with(".tar.zst.idx")
+                || archive_name.ends_with(".tar.xz.bak"),
+            "Unexpected format of {}",
+            archive_name
+        );
+    }
+
+    /* Archives of the same name in other formats are reported */
+    let archive_path = maildir.packed_dir.join("2005-05.tar.zst");
+    let duplicate_path = maildir.packed_dir.join("2005-05.tar.gz");
+    fs::copy(&archive_path, &duplicate_path)?;
+    let duplicate_hash = hash_content(File::open(&duplicate_path)?)?;
+    let email = ALL_EMAILS["2005-05"][0];
+    maildir.fill_maildir([email].iter())?;
+    let assert = maildir
+        .execute_packing_assert(&["--compression", "zstd"])
+        .success();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("Warning: ignoring") && stderr.contains("2005-05.tar.gz"),
+        "{}",
+        stderr
+    );
+    check_empty_maildir(&maildir)?;
+    assert_eq!(hash_content(File::open(&duplicate_path)?)?, duplicate_hash);
+    Ok(())
+}
+
+#[test]
+fn all_compression_packing() -> io::Result<()> {
+    for codec in &["xz", "zstd", "gzip", "bzip2", "none"] {
+        let maildir = TempMaildir::new("all_compression_packing")?;
+        let emails = generate_email_set(ALL_EMAILS["2005-05"].iter());
+        maildir.fill_maildir(emails.iter())?;
+        maildir.execute_packing_with(&["--compression", codec]);
+        let expected = generate_expected_result(&emails);
+        check_packed(&maildir, expected, HashMap::new())?;
+        check_empty_maildir(&maildir)?;
+    }
+    Ok(())
+}
+
+/// Returns all files under the given directory with hashes of their content.
+fn snapshot_dir(dir: &Path) -> io::Result<HashMap<PathBuf, HashResult>> {
+    let mut result = HashMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            result.extend(snapshot_dir(&path)?);
+        } else {
+            let hash = hash_content(File::open(&path)?)?;
+            result.insert(path, hash);
+        }
+    }
+    Ok(result)
+}
+
+#[test]
+fn dry_run() -> io::Result<()> {
+    let maildir = TempMaildir::new("dry_run")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    let initial_set: HashSet<_> = ALL_EMAILS["2005-05"].iter().copied().collect();
+
+    /* Dry run on a maildir which has never been packed */
+    maildir.fill_maildir(initial_set.iter())?;
+    let snapshot = snapshot_dir(maildir.path())?;
+    let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .args(["--quiet", "--dry-run"])
+        .arg(maildir.path())
+        .output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let expected = format!(
+        "2005-05.tar.xz: create, {} new email(s)\n\
+         INBOX: 1 archive(s) to create, 0 to update, {} email(s) to add, 0 without date\n",
+        initial_set.len(),
+        initial_set.len(),
+    );
+    assert_eq!(stdout, expected);
+    assert_eq!(snapshot_dir(maildir.path())?, snapshot);
+
+    /* Dry run after packing */
+    maildir.execute_packing();
+    maildir.fill_maildir(emails.iter())?;
+    let snapshot = snapshot_dir(maildir.path())?;
+    let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .args(["--quiet", "--dry-run"])
+        .arg(maildir.path())
+        .output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    let expected_line = format!(
+        "2005-05.tar.xz: update, 0 new email(s), {} already archived",
+        initial_set.len()
+    );
+    assert!(lines.contains(&expected_line.as_str()), "{}", stdout);
+    let expected_line = format!(
+        "unknown.tar.xz: create, {} new email(s)",
+        ALL_EMAILS["unknown"].len()
+    );
+    assert!(lines.contains(&expected_line.as_str()), "{}", stdout);
+    let expected_line = format!(
+        "INBOX: {} archive(s) to create, 1 to update, {} email(s) to add, {} without date",
+        ALL_EMAILS.len() - 1,
+        emails.len() - initial_set.len(),
+        ALL_EMAILS["unknown"].len(),
+    );
+    assert_eq!(lines.last(), Some(&expected_line.as_str()));
+    assert_eq!(snapshot_dir(maildir.path())?, snapshot);
+    Ok(())
+}
+
+#[test]
+fn keep_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("keep_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    let initial_set: HashSet<_> = emails
+        .iter()
+        .copied()
+        .filter(|email| !email.starts_with(EMAILS_PATH.join("ruby-mail").join("2005-05")))
+        .collect();
+    assert_ne!(initial_set.len(), emails.len());
+
+    /* Initial packing keeps emails in the maildir */
+    maildir.fill_maildir(initial_set.iter())?;
+    maildir.execute_packing_with(&["--keep"]);
+    let expected = generate_expected_result(&initial_set);
+    check_packed(&maildir, expected, HashMap::new())?;
+    let maildir_snapshot = snapshot_dir(&maildir.new_dir)?;
+    assert_eq!(maildir_snapshot.len(), initial_set.len());
+
+    /* Packing again changes nothing */
+    let packed_snapshot = snapshot_dir(&maildir.packed_dir)?;
+    maildir.execute_packing_with(&["--keep"]);
+    assert_eq!(snapshot_dir(&maildir.packed_dir)?, packed_snapshot);
+    assert_eq!(snapshot_dir(&maildir.new_dir)?, maildir_snapshot);
+
+    /* New emails only update their archive */
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_with(&["--keep"]);
+    let expected = generate_expected_result(&emails);
+    let archive_name = format!("2005-05{}", ARCHIVE_SUFFIX);
+    let expected_backup = HashMap::new();
+    check_packed(&maildir, expected, expected_backup)?;
+    let mut changed: Vec<_> = snapshot_dir(&maildir.packed_dir)?
+        .into_iter()
+        .filter(|(path, hash)| packed_snapshot.get(path) != Some(hash))
+        .map(|(path, _)| path)
+        .collect();
+    changed.sort();
+    let archive_path = maildir.packed_dir.join(archive_name);
+    let index_path = archive_path.with_extension("xz.idx");
+    assert_eq!(changed, vec![archive_path, index_path]);
+    assert_eq!(snapshot_dir(&maildir.new_dir)?.len(), emails.len());
+    Ok(())
+}
+
+#[test]
+fn older_than_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("older_than_packing")?;
+    // Emails without a date fall back to their mtime, which is recent.
+    let emails = generate_email_set(
+        ALL_EMAILS
+            .iter()
+            .filter(|(&name, _)| name != "unknown")
+            .flat_map(|(_, l)| l.iter()),
+    );
+    let recent = maildir.new_dir.join("recent");
+    let content = format!(
+        "Date: {}\r\nSubject: recent\r\n\r\nHello\r\n",
+        chrono::Utc::now().to_rfc2822()
+    );
+    fs::write(&recent, content)?;
+
+    /* Only old emails are packed */
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_with(&["--older-than", "30d"]);
+    let expected = generate_expected_result(&emails);
+    check_packed(&maildir, expected, HashMap::new())?;
+    let remaining: Vec<_> = snapshot_dir(&maildir.new_dir)?.into_keys().collect();
+    assert_eq!(remaining, vec![recent.clone()]);
+
+    /* Freshly copied files are recent by mtime */
+    let packed_snapshot = snapshot_dir(&maildir.packed_dir)?;
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_with(&["--older-than", "1d", "--age-basis", "mtime"]);
+    assert_eq!(snapshot_dir(&maildir.packed_dir)?, packed_snapshot);
+    assert_eq!(snapshot_dir(&maildir.new_dir)?.len(), emails.len() + 1);
+
+    /* Emails of the current period are left alone */
+    maildir.execute_packing_with(&["--skip-current"]);
+    assert_eq!(snapshot_dir(&maildir.packed_dir)?, packed_snapshot);
+    let remaining: Vec<_> = snapshot_dir(&maildir.new_dir)?.into_keys().collect();
+    assert_eq!(remaining, vec![recent]);
+    Ok(())
+}
+
+#[test]
+fn failed_archive_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("failed_archive_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    let broken_set = generate_email_set(ALL_EMAILS["2005-05"].iter());
+    let archive = maildir
+        .packed_dir
+        .join(format!("2005-05{}", ARCHIVE_SUFFIX));
+    fs::create_dir_all(&maildir.packed_dir)?;
+    fs::write(&archive, "not an archive")?;
+
+    /* Other archives are still packed */
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_assert(&[]).code(4);
+    assert_eq!(fs::read(&archive)?, b"not an archive");
+    let remaining: HashSet<_> = fs::read_dir(&maildir.new_dir)?
+        .map(|entry| Ok(entry?.file_name()))
+        .collect::<io::Result<_>>()?;
+    let expected_remaining: HashSet<_> = broken_set
+        .iter()
+        .map(|email| email.file_name().unwrap().to_os_string())
+        .collect();
+    assert_eq!(remaining, expected_remaining);
+
+    fs::remove_file(&archive)?;
+    let packed_set = emails.difference(&broken_set).copied().collect();
+    let expected = generate_expected_result(&packed_set);
+    check_packed(&maildir, expected, HashMap::new())
+}
+
+#[test]
+fn conflicting_email_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("conflicting_email_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing();
+
+    /* An email with the same name but different content is skipped */
+    let email = ALL_EMAILS["2005-05"][0];
+    let conflicting = maildir.new_dir.join(email.file_name().unwrap());
+    fs::write(&conflicting, "Date: Sun, 1 May 2005 00:00:00 +0000\r\n\r\n")?;
+    let packed_snapshot = snapshot_dir(&maildir.packed_dir)?;
+    maildir.execute_packing_assert(&[]).code(3);
+    assert_eq!(snapshot_dir(&maildir.packed_dir)?, packed_snapshot);
+    assert!(conflicting.is_file());
+    Ok(())
+}
+
+#[test]
+fn recover_interrupted_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("recover_interrupted_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    let initial_set: HashSet<_> = emails
+        .iter()
+        .copied()
+        .filter(|&email| email != ALL_EMAILS["2005-05"][0])
+        .collect();
+    // Use uncompressed archives, so that they can be broken precisely.
+    let archive = maildir.packed_dir.join("2005-05.tar");
+    let mut tmp = archive.clone().into_os_string();
+    tmp.push(".tmp");
+    let mut backup = archive.clone().into_os_string();
+    backup.push(".bak");
+    // Temporary archives are created with restricted permission.
+    let write_tmp = |content: &[u8]| -> io::Result<()> {
+        fs::write(&tmp, content)?;
+        fs::set_permissions(&tmp, fs::Permissions::from_mode(0o600))
+    };
+
+    /* Produce the old and the new content of the archive */
+    maildir.fill_maildir(initial_set.iter())?;
+    maildir.execute_packing_with(&["-c", "none"]);
+    let old_content = fs::read(&archive)?;
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_with(&["-c", "none"]);
+    let new_content = fs::read(&archive)?;
+
+    /* Interrupted after the backup is linked, but before renaming */
+    fs::write(&archive, &old_content)?;
+    fs::remove_file(&backup)?;
+    fs::hard_link(&archive, &backup)?;
+    write_tmp(&new_content)?;
+    maildir.execute_packing_with(&["-c", "none"]);
+    let expected = generate_expected_result(&emails);
+    let expected_backup = HashMap::from([("2005-05", hash_content(&old_content[..])?)]);
+    check_packed(&maildir, expected, expected_backup.clone())?;
+
+    /* Interrupted while writing the temporary archive */
+    write_tmp(&new_content[..new_content.len() / 2])?;
+    maildir.execute_packing_with(&["-c", "none"]);
+    let expected = generate_expected_result(&emails);
+    check_packed(&maildir, expected, expected_backup)?;
+
+    /* The archive is broken, and its backup is restored */
+    let mut broken_content = new_content.clone();
+    // Replace the end of the archive with an invalid header.
+    let end = broken_content.len() - 1024;
+    broken_content[end..end + 512].fill(b'x');
+    fs::write(&archive, &broken_content)?;
+    write_tmp(&new_content[..new_content.len() / 2])?;
+    let output = maildir.execute_packing_assert(&["-c", "none"]).success();
+    let stderr = String::from_utf8_lossy(&output.get_output().stderr);
+    let lost_name = ALL_EMAILS["2005-05"][0].file_name().unwrap();
+    assert!(stderr.contains("restored from its backup"), "{}", stderr);
+    assert!(
+        stderr.contains(&format!("{:?} of 2005-05", lost_name)),
+        "{}",
+        stderr
+    );
+    assert_eq!(fs::read(&archive)?, old_content);
+    Ok(())
+}
+
+#[test]
+fn verified_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("verified_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    let initial_set: HashSet<_> = emails
+        .iter()
+        .copied()
+        .filter(|&email| email != ALL_EMAILS["2005-05"][0])
+        .collect();
+
+    /* Initial packing */
+    maildir.fill_maildir(initial_set.iter())?;
+    maildir.execute_packing_with(&["--verify"]);
+    let expected = generate_expected_result(&initial_set);
+    check_packed(&maildir, expected, HashMap::new())?;
+    check_empty_maildir(&maildir)?;
+
+    /* Only the updated archive gets a backup */
+    let file_name = format!("2005-05{}", ARCHIVE_SUFFIX);
+    let old_hash = hash_content(File::open(maildir.packed_dir.join(&file_name))?)?;
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing_with(&["--verify"]);
+    let expected = generate_expected_result(&emails);
+    check_packed(&maildir, expected, HashMap::from([("2005-05", old_hash)]))?;
+    check_empty_maildir(&maildir)?;
+
+    /* A failed verification leaves the emails and the archive alone */
+    // A corrupted index makes the appended archive differ from what is
+    // expected.
+    let archive_path = maildir.packed_dir.join(&file_name);
+    let index_path = maildir.packed_dir.join(format!("{}.idx", file_name));
+    let mut index: serde_json::Value = serde_json::from_slice(&fs::read(&index_path)?)?;
+    index["entries"][0]["sha512"] = "0".repeat(128).into();
+    fs::write(&index_path, serde_json::to_vec(&index)?)?;
+    let old_hash = hash_content(File::open(&archive_path)?)?;
+    let email_path = maildir.new_dir.join("1116115200.late");
+    fs::write(
+        &email_path,
+        "Date: Sun, 15 May 2005 00:00:00 +0000\nSubject: late\n\nlate\n",
+    )?;
+    let assert = maildir
+        .execute_packing_assert(&["--append", "--verify"])
+        .code(4);
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("failed to verify the archive"),
+        "{}",
+        stderr
+    );
+    assert!(email_path.is_file());
+    assert_eq!(hash_content(File::open(&archive_path)?)?, old_hash);
+    assert!(!maildir
+        .packed_dir
+        .join(format!("{}.append", file_name))
+        .exists());
+
+    /* With --verify, emails are checked against the archive, not its index */
+    maildir.execute_packing_with(&["--verify"]);
+    assert!(!email_path.exists());
+    let content = "Date: Sun, 15 May 2005 00:00:01 +0000\nSubject: indexed\n\nindexed\n";
+    let email_path = maildir.new_dir.join("1116115201.indexed");
+    fs::write(&email_path, content)?;
+    // The index claims the archive holds the new email.
+    let mut index: serde_json::Value = serde_json::from_slice(&fs::read(&index_path)?)?;
+    index["entries"][0]["name"] = "1116115201.indexed".into();
+    index["entries"][0]["sha512"] = format!("{:x}", Sha512::digest(content)).into();
+    fs::write(&index_path, serde_json::to_vec(&index)?)?;
+    maildir.execute_packing_with(&["--verify"]);
+    assert!(!email_path.exists());
+    let mut tar_archive = TarArchive::new(XzDecoder::new_multi_decoder(File::open(&archive_path)?));
+    tar_archive.set_ignore_zeros(true);
+    let mut names = Vec::new();
+    for entry in tar_archive.entries()? {
+        names.push(entry?.header().path()?.into_owned());
+    }
+    assert!(names.contains(&PathBuf::from("1116115201.indexed")));
+    Ok(())
+}
+
+#[test]
+fn locked_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("locked_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir(emails.iter())?;
+    let lock_path = maildir.path().join("packed.lock");
+    let lock = File::create(&lock_path)?;
+    lock.lock_exclusive()?;
+
+    /* Fail while another run holds the lock */
+    maildir.execute_packing_assert(&[]).failure();
+    assert!(!maildir.packed_dir.exists());
+
+    /* Wait for the lock to be released */
+    let holder = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        drop(lock);
+    });
+    maildir.execute_packing_with(&["--wait"]);
+    holder.join().unwrap();
+    let expected = generate_expected_result(&emails);
+    check_packed(&maildir, expected, HashMap::new())?;
+    check_empty_maildir(&maildir)?;
+
+    /* A lock left by a dead process doesn't block */
+    fs::write(&lock_path, "99999999\n")?;
+    maildir.execute_packing();
+    assert_eq!(fs::read(&lock_path)?, b"");
+
+    /* Subcommands which modify archives wait for the lock as well */
+    let lock = File::create(&lock_path)?;
+    lock.lock_exclusive()?;
+    Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("compact")
+        .arg(maildir.path())
+        .assert()
+        .failure();
+    let holder = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(500));
+        drop(lock);
+    });
+    Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .args(["compact", "--wait"])
+        .arg(maildir.path())
+        .assert()
+        .success();
+    holder.join().unwrap();
+    Ok(())
+}
+
+#[test]
+fn list_archives() -> io::Result<()> {
+    let maildir = TempMaildir::new("list_archives")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing();
+
+    let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .args(["list", "--entries", "--json"])
+        .arg(maildir.path())
+        .output()?;
+    assert!(output.status.success());
+    let list: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let list = list.as_array().unwrap();
+    let expected = generate_expected_result(&emails);
+    assert_eq!(list.len(), expected.len());
+    for archive in list {
+        let path = archive["path"].as_str().unwrap();
+        let name = path.strip_suffix(ARCHIVE_SUFFIX).unwrap();
+        let expected_content = &expected[name];
+        let entries = archive["entries"].as_array().unwrap();
+        assert_eq!(archive["emails"], expected_content.len());
+        assert_eq!(entries.len(), expected_content.len());
+        let file = maildir.packed_dir.join(path);
+        assert_eq!(archive["compressed_size"], fs::metadata(file)?.len());
+        let mut uncompressed_size = 0;
+        for entry in entries {
+            let file_name = entry["name"].as_str().unwrap();
+            let size = entry["size"].as_u64().unwrap();
+            assert!(expected_content.contains_key(OsStr::new(file_name)));
+            assert_eq!(entry["sha512"].as_str().unwrap().len(), 128);
+            uncompressed_size += size;
+        }
+        assert_eq!(archive["uncompressed_size"], uncompressed_size);
+        if name != "unknown" {
+            assert!(archive["first_date"].is_string());
+            assert!(archive["last_date"].is_string());
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn unpack_archives() -> io::Result<()> {
+    let maildir = TempMaildir::new("unpack_archives")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing();
+    let unpack = |args: &[&OsStr]| {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg("unpack")
+            .arg(maildir.path())
+            .args(args)
+            .assert()
+            .success();
+    };
+
+    /* Restore a single email and remove it from the archive */
+    let email = ALL_EMAILS["2005-05"][0];
+    let file_name = email.file_name().unwrap();
+    let old_hash = hash_content(File::open(
+        maildir
+            .packed_dir
+            .join(format!("2005-05{}", ARCHIVE_SUFFIX)),
+    )?)?;
+    let pattern = file_name.to_str().unwrap().replace('.', "?");
+    unpack(&[
+        OsStr::new("2005-05"),
+        OsStr::new("--entry"),
+        OsStr::new(&pattern),
+        OsStr::new("--remove"),
+        OsStr::new("--threads"),
+        OsStr::new("2"),
+    ]);
+    assert_eq!(
+        hash_content(File::open(maildir.new_dir.join(file_name))?)?,
+        EMAIL_HASHS[email]
+    );
+    let remaining = generate_email_set(emails.iter().filter(|&e| e != &email));
+    let expected = generate_expected_result(&remaining);
+    check_packed(&maildir, expected, HashMap::from([("2005-05", old_hash)]))?;
+    // The index is rewritten along with the archive.
+    let archive_path = maildir
+        .packed_dir
+        .join(format!("2005-05{}", ARCHIVE_SUFFIX));
+    let index_path = maildir
+        .packed_dir
+        .join(format!("2005-05{}.idx", ARCHIVE_SUFFIX));
+    let index: serde_json::Value = serde_json::from_slice(&fs::read(index_path)?)?;
+    assert_eq!(index["archive_size"], fs::metadata(&archive_path)?.len());
+    assert_eq!(
+        index["entries"].as_array().unwrap().len(),
+        ALL_EMAILS["2005-05"].len() - 1
+    );
+
+    /* Restore a whole archive into cur of another maildir */
+    let target = maildir.path().join("restored");
+    unpack(&[
+        OsStr::new("2005-05"),
+        OsStr::new("--to"),
+        target.as_os_str(),
+        OsStr::new("--cur"),
+    ]);
+    let restored = snapshot_dir(&target.join("cur"))?;
+    assert_eq!(restored.len(), ALL_EMAILS["2005-05"].len() - 1);
+    for &email in ALL_EMAILS["2005-05"].iter().filter(|&e| e != &email) {
+        let mut file_name = email.file_name().unwrap().to_os_string();
+        file_name.push(":2,");
+        assert_eq!(
+            restored[&target.join("cur").join(file_name)],
+            EMAIL_HASHS[email]
+        );
+    }
+
+    /* Existing emails are never overwritten */
+    let existing = target.join("cur").join(
+        fs::read_dir(target.join("cur"))?
+            .next()
+            .unwrap()?
+            .file_name(),
+    );
+    fs::write(&existing, "modified")?;
+    unpack(&[OsStr::new("--to"), target.as_os_str(), OsStr::new("--cur")]);
+    assert_eq!(fs::read(&existing)?, b"modified");
+    assert_eq!(fs::read_dir(target.join("tmp"))?.count(), 0);
+    Ok(())
+}
+
+#[test]
+fn search_archives() -> io::Result<()> {
+    let maildir = TempMaildir::new("search_archives")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing();
+    let search = |args: &[&OsStr]| {
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg("search")
+            .arg(maildir.path())
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+
+    // Find emails with the subject in their header section.
+    let expected: HashSet<_> = emails
+        .iter()
+        .filter(|email| {
+            let content = fs::read_to_string(email).unwrap_or_default();
+            content
+                .lines()
+                .take_while(|line| !line.is_empty())
+                .any(|line| line.starts_with("Subject:") && line.contains("Saying Hello"))
+        })
+        .map(|email| email.file_name().unwrap().to_str().unwrap())
+        .collect();
+    assert!(!expected.is_empty());
+
+    /* Print names of matching emails */
+    let output = search(&[OsStr::new("--subject"), OsStr::new("saying hello")]);
+    let found: HashSet<_> = output
+        .lines()
+        .map(|line| line.split_once(": ").unwrap().1)
+        .collect();
+    assert_eq!(found, expected);
+
+    /* Extract matching emails */
+    let dir = maildir.path().join("found");
+    search(&[
+        OsStr::new("--subject"),
+        OsStr::new("saying hello"),
+        OsStr::new("--until"),
+        OsStr::new("2000-01-01"),
+        OsStr::new("--extract"),
+        dir.as_os_str(),
+    ]);
+    let extracted = snapshot_dir(&dir)?;
+    assert_eq!(extracted.len(), expected.len());
+    for &email in emails.iter() {
+        if let Some(hash) = extracted.get(&dir.join(email.file_name().unwrap())) {
+            assert_eq!(*hash, EMAIL_HASHS[email]);
+        }
+    }
+
+    /* Print matching emails in mbox format */
+    let output = search(&[
+        OsStr::new("--subject"),
+        OsStr::new("saying hello"),
+        OsStr::new("--mbox"),
+    ]);
+    let from_lines = output
+        .lines()
+        .filter(|line| line.starts_with("From "))
+        .count();
+    assert_eq!(from_lines, expected.len());
+
+    /* Nothing matches out of the date range */
+    let output = search(&[
+        OsStr::new("--subject"),
+        OsStr::new("saying hello"),
+        OsStr::new("--since"),
+        OsStr::new("2000-01-01"),
+    ]);
+    assert_eq!(output, "");
+    Ok(())
+}
+
+#[test]
+fn indexed_archives() -> io::Result<()> {
+    let maildir = TempMaildir::new("indexed_archives")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing();
+    let expected = generate_expected_result(&emails);
+    let index_path = |name: &str| {
+        maildir
+            .packed_dir
+            .join(format!("{}{}.idx", name, ARCHIVE_SUFFIX))
+    };
+    for name in expected.keys() {
+        assert!(index_path(name).is_file(), "Index of {} not found", name);
+    }
+    let list = || {
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args(["list", "--entries", "--json"])
+            .arg(maildir.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    };
+    let indexed_list = list();
+
+    /* Packing the same emails again is answered from the index */
+    maildir.fill_maildir(emails.iter())?;
+    maildir.execute_packing();
+    check_packed(&maildir, expected.clone(), HashMap::new())?;
+    assert_eq!(list(), indexed_list);
+
+    /* Stale or missing indexes are not used */
+    fs::copy(index_path("2005-05"), index_path("2009-12"))?;
+    fs::remove_file(index_path("2005-05"))?;
+    assert_eq!(list(), indexed_list);
+
+    /* Rebuild missing and stale indexes quietly */
+    Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .args(["index", "--quiet"])
+        .arg(maildir.path())
+        .assert()
+        .success()
+        .stderr("");
+    assert!(index_path("2005-05").is_file());
+    assert_eq!(list(), indexed_list);
+    check_packed(&maildir, expected, HashMap::new())?;
+    Ok(())
+}
+
+#[test]
+fn mbox_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("mbox_packing")?;
+    let emails = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    let mut messages: Vec<Vec<u8>> = emails.iter().map(fs::read).collect::<io::Result<_>>()?;
+    messages.push(
+        b"Date: Fri, 1 Jan 1999 00:00:00 +0000\nSubject: quoted\n\nFrom here\n>From there\n"
+            .to_vec(),
+    );
+    // Write the messages in mboxrd format.
+    let mut mbox = vec![];
+    for message in &messages {
+        mbox.extend_from_slice(b"From someone@example.com Mon Jan  2 03:04:05 2006\n");
+        for line in message.split_inclusive(|&b| b == b'\n') {
+            let quotes = line.iter().take_while(|&&b| b == b'>').count();
+            if line[quotes..].starts_with(b"From ") {
+                mbox.push(b'>');
+            }
+            mbox.extend_from_slice(line);
+        }
+        if !message.ends_with(b"\n") {
+            mbox.push(b'\n');
+        }
+        mbox.push(b'\n');
+    }
+    let mbox_path = maildir.path().join("old.mbox");
+    fs::write(&mbox_path, &mbox)?;
+    let mbox_arg = mbox_path.to_str().unwrap();
+    let list = || {
+        let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .args(["list", "--entries", "--json"])
+            .arg(maildir.path())
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()
+    };
+
+    /* Messages are packed into the same archives as emails */
+    maildir.execute_packing_with(&["--mbox", mbox_arg]);
+    let list_output = list();
+    let mut archives: Vec<_> = list_output
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|archive| archive["path"].as_str().unwrap().to_string())
+        .collect();
+    archives.sort();
+    let mut expected: Vec<_> = generate_expected_result(&emails)
+        .keys()
+        .map(|name| format!("{}{}", name, ARCHIVE_SUFFIX))
+        .chain([format!("1999-01{}", ARCHIVE_SUFFIX)])
+        .collect();
+    expected.sort();
+    assert_eq!(archives, expected);
+    assert_eq!(fs::read(&mbox_path)?, mbox);
+    assert!(!maildir.packed_dir.join(".import").exists());
+
+    /* Restored messages have the content of the original ones */
+    let restore_dir = maildir.path().join("restored");
+    Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("unpack")
+        .arg(maildir.path())
+        .arg("--to")
+        .arg(&restore_dir)
+        .assert()
+        .success();
+    let mut restored: Vec<_> = fs::read_dir(restore_dir.join("new"))?
+        .map(|entry| {
+            let content = fs::read(entry?.path())?;
+            let pos = content.iter().position(|&b| b == b'\n').unwrap();
+            assert!(content.starts_with(b"From someone@example.com "));
+            Ok(content[pos + 1..].to_vec())
+        })
+        .collect::<io::Result<_>>()?;
+    restored.sort();
+    for message in &mut messages {
+        if !message.ends_with(b"\n") {
+            message.push(b'\n');
+        }
+    }
+    messages.sort();
+    messages.dedup();
+    assert_eq!(restored, messages);
+
+    /* Packing the same mbox again adds nothing */
+    maildir.execute_packing_with(&["--mbox", mbox_arg]);
+    assert_eq!(list(), list_output);
+    Ok(())
+}
+
+#[test]
+fn mbox_packing_options() -> io::Result<()> {
+    let maildir = TempMaildir::new("mbox_packing_options")?;
+    // The second message is dated by the mtime of the mbox file, i.e. now.
+    let mbox = b"From someone@example.com Mon Jan  2 03:04:05 2006\n\
+                 Date: Fri, 1 Jan 1999 00:00:00 +0000\nSubject: old\n\nold\n\n\
+                 From someone@example.com Mon Jan  2 03:04:05 2006\n\
+                 Subject: new\n\nnew\n\n";
+    let mbox_path = maildir.path().join("old.mbox");
+    fs::write(&mbox_path, mbox)?;
+    let mbox_arg = mbox_path.to_str().unwrap();
+    let args = ["--mbox", mbox_arg, "--date-sources", "date,mtime"];
+
+    /* Messages are planned without being written by a dry run */
+    let snapshot = snapshot_dir(maildir.path())?;
+    let output = Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .args(["--quiet", "--dry-run", "--older-than", "30d"])
+        .args(args)
+        .arg(maildir.path())
+        .output()?;
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "1999-01.tar.xz: create, 1 new email(s)\n\
+         INBOX: 1 archive(s) to create, 0 to update, 1 email(s) to add, 0 without date\n"
+    );
+    assert_eq!(snapshot_dir(maildir.path())?, snapshot);
+
+    /* Recent messages are skipped, and no copies are left with --keep */
+    maildir.execute_packing_with(&[&args[..], &["--older-than", "30d", "--keep"]].concat());
+    let mut files: Vec<_> = fs::read_dir(&maildir.packed_dir)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<io::Result<_>>()?;
+    files.sort();
+    let archive_name = format!("1999-01{}", ARCHIVE_SUFFIX);
+    assert_eq!(
+        files,
+        [archive_name.clone(), format!("{}.idx", archive_name)]
+    );
+    Ok(())
+}
+
+/// Splits the mbox archive into messages, unquoting `From ` lines in their
+/// content as mboxrd does.
+fn read_mbox_archive(path: &Path) -> io::Result<Vec<Vec<u8>>> {
+    let mut data = vec![];
+    XzDecoder::new_multi_decoder(File::open(path)?).read_to_end(&mut data)?;
+    assert!(data.starts_with(b"From "));
+    let mut messages: Vec<Vec<u8>> = vec![];
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        if line.starts_with(b"From ") {
+            messages.push(vec![]);
+            continue;
+        }
+        let message = messages.last_mut().unwrap();
+        let quotes = line.iter().take_while(|&&b| b == b'>').count();
+        if quotes > 0 && line[quotes..].starts_with(b"From ") {
+            message.extend_from_slice(&line[1..]);
+        } else {
+            message.extend_from_slice(line);
+        }
+    }
+    for message in &mut messages {
+        // Remove the empty line which separates messages.
+        assert_eq!(message.pop(), Some(b'\n'));
+        assert!(message.is_empty() || message.ends_with(b"\n"));
+    }
+    Ok(messages)
+}
+
+/// Checks that each archive is an mbox file with the content of the expected
+/// emails, which are stored without their `From ` line.
+fn check_mbox_packed(maildir: &TempMaildir, email_set: &HashSet<&'static Path>) -> io::Result<()> {
+    let mut archives = HashSet::new();
+    for (&archive, emails) in ALL_EMAILS.iter() {
+        let mut expected: Vec<_> = emails
+            .iter()
+            .filter(|&email| email_set.contains(email))
+            .map(|email| {
+                let mut content = fs::read(email)?;
+                // A `From` header field may have spaces before the colon.
+                let is_from_line = content.starts_with(b"From ")
+                    && content[5..].iter().find(|&&b| b != b' ') != Some(&b':');
+                if is_from_line {
+                    let pos = content.iter().position(|&b| b == b'\n').unwrap();
+                    content.drain(..=pos);
+                }
+                if !content.is_empty() && !content.ends_with(b"\n") {
+                    content.push(b'\n');
+                }
+                Ok(content)
+            })
+            .collect::<io::Result<_>>()?;
+        if expected.is_empty() {
+            continue;
+        }
+        expected.sort();
+        expected.dedup();
+        let file_name = format!("{}.mbox.xz", archive);
+        let mut messages = read_mbox_archive(&maildir.packed_dir.join(&file_name))?;
+        messages.sort();
+        assert_eq!(messages, expected, "{} has unexpected messages", file_name);
+        archives.insert(file_name.clone());
+        archives.insert(format!("{}.idx", file_name));
+    }
+    for entry in fs::read_dir(&maildir.packed_dir)? {
+        let file_name = entry?.file_name().into_string().unwrap();
+        if !file_name.ends_with(".bak") {
+            assert!(archives.contains(&file_name), "unexpected {}", file_name);
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn mbox_output_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("mbox_output_packing")?;
+    let archives: Vec<_> = ALL_EMAILS
+        .iter()
+        .filter(|&(_, emails)| emails.len() >= 2)
+        .collect();
+    let initial_set = generate_email_set(
+        archives
+            .iter()
+            .flat_map(|&(_, emails)| emails[..emails.len() * 2 / 3].iter()),
+    );
+    let second_set = generate_email_set(
+        archives
+            .iter()
+            .flat_map(|&(_, emails)| emails[emails.len() / 3..].iter()),
+    );
+
+    /* Initial packing */
+    maildir.fill_maildir(initial_set.iter())?;
+    maildir.execute_packing_with(&["--format", "mbox"]);
+    check_mbox_packed(&maildir, &initial_set)?;
+    check_empty_maildir(&maildir)?;
+
+    /* Emails already in the archives are not added again */
+    maildir.fill_maildir(second_set.iter())?;
+    maildir.execute_packing_with(&["--format", "mbox"]);
+    let merged = second_set.union(&initial_set).copied().collect();
+    check_mbox_packed(&maildir, &merged)?;
+    check_empty_maildir(&maildir)?;
+
+    /* Appending adds a new stream with new emails only */
+    let all_set = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()));
+    maildir.fill_maildir(all_set.iter())?;
+    maildir.execute_packing_with(&["--format", "mbox", "--append"]);
+    check_mbox_packed(&maildir, &all_set)?;
+    check_empty_maildir(&maildir)?;
+
+    /* Archives are converted back into tar */
+    let archive = archives[0].0;
+    maildir.fill_maildir(ALL_EMAILS[archive].iter())?;
+    maildir.execute_packing();
+    assert!(maildir
+        .packed_dir
+        .join(format!("{}{}", archive, ARCHIVE_SUFFIX))
+        .is_file());
+    assert!(!maildir
+        .packed_dir
+        .join(format!("{}.mbox.xz", archive))
+        .exists());
+    check_empty_maildir(&maildir)
+}
+
+#[test]
+fn mh_packing() -> io::Result<()> {
+    let maildir = TempMaildir::new("mh_packing")?;
+    let emails: Vec<_> = generate_email_set(ALL_EMAILS.values().flat_map(|l| l.iter()))
+        .into_iter()
+        .collect();
+    // Write the emails as an MH folder, with every third one unseen.
+    let mh_dir = maildir.path().join("inbox");
+    fs::create_dir(&mh_dir)?;
+    let mut unseen = vec![];
+    for (i, email) in emails.iter().enumerate() {
+        let number = i + 1;
+        fs::copy(email, mh_dir.join(number.to_string()))?;
+        if number % 3 == 0 {
+            unseen.push(number.to_string());
+        }
+    }
+    fs::write(
+        mh_dir.join(".mh_sequences"),
+        format!("cur: 1\nunseen: {}\n", unseen.join(" ")),
+    )?;
+    // Removed messages and other files are skipped.
+    fs::write(mh_dir.join(",1"), "Subject: removed\n\n")?;
+    fs::write(mh_dir.join(".xmhcache"), "")?;
+    let mh_arg = mh_dir.to_str().unwrap();
+    let unpack = |dir: &Path| -> io::Result<Vec<Vec<u8>>> {
+        Command::cargo_bin(env!("CARGO_PKG_NAME"))
+            .unwrap()
+            .arg("unpack")
+            .arg(maildir.path())
+            .arg("--to")
+            .arg(dir)
+            .assert()
+            .success();
+        let mut restored: Vec<_> = fs::read_dir(dir.join("new"))?
+            .map(|entry| fs::read(entry?.path()))
+            .collect::<io::Result<_>>()?;
+        restored.sort();
+        Ok(restored)
+    };
+    let expected = |skip_unseen: bool| -> io::Result<Vec<Vec<u8>>> {
+        let mut messages: Vec<_> = emails
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| !skip_unseen || (i + 1) % 3 != 0)
+            .map(|(_, email)| fs::read(email))
+            .collect::<io::Result<_>>()?;
+        messages.sort();
+        messages.dedup();
+        Ok(messages)
+    };
+
+    /* Unseen messages are skipped */
+    maildir.execute_packing_with(&["--mh", mh_arg, "--mh-exclude-sequence", "unseen"]);
+    assert_eq!(unpack(&maildir.path().join("restored"))?, expected(true)?);
+    assert!(mh_dir.join("1").is_file());
+    assert!(!maildir.packed_dir.join(".import").exists());
+
+    /* The rest are added when packing the folder again, even with --keep */
+    maildir.execute_packing_with(&["--mh", mh_arg, "--keep"]);
+    assert_eq!(unpack(&maildir.path().join("restored2"))?, expected(false)?);
+    assert!(!maildir.packed_dir.join(".import").exists());
+    Ok(())
+}
+
+#[test]
+fn mbox_output_from_lines() -> io::Result<()> {
+    let maildir = TempMaildir::new("mbox_output_from_lines")?;
+    let archive_path = maildir.packed_dir.join("2017-07.mbox.xz");
+    let from_lines = || -> io::Result<Vec<String>> {
+        let mut data = String::new();
+        XzDecoder::new_multi_decoder(File::open(&archive_path)?).read_to_string(&mut data)?;
+        let mut lines: Vec<_> = data
+            .lines()
+            .filter(|line| line.starts_with("From "))
+            .map(str::to_string)
+            .collect();
+        lines.sort();
+        Ok(lines)
+    };
+    let args = ["--format", "mbox", "--date-sources", "date,file-name"];
+
+    // Dated by its file name only.
+    fs::write(
+        maildir.new_dir.join("1500000000.M1P1.host"),
+        "Return-Path: <a@example.com>\nSubject: a\n\nbody\n",
+    )?;
+    // An imported message with its own `From ` line.
+    fs::write(
+        maildir.new_dir.join("1500000100.M2P2.host"),
+        "From b@example.com Mon Jul 17 00:00:00 2017\nSubject: b\n\nbody\n",
+    )?;
+    maildir.execute_packing_with(&args);
+    let expected = vec![
+        "From a@example.com Fri Jul 14 02:40:00 2017".to_string(),
+        "From b@example.com Mon Jul 17 00:00:00 2017".to_string(),
+    ];
+    assert_eq!(from_lines()?, expected);
+
+    /* `From ` lines of existing messages are kept when merging */
+    fs::write(
+        maildir.new_dir.join("1500000200.M3P3.host"),
+        "Return-Path: <c@example.com>\nSubject: c\n\nbody\n",
+    )?;
+    maildir.execute_packing_with(&args);
+    let mut expected = expected;
+    expected.push("From c@example.com Fri Jul 14 02:43:20 2017".to_string());
+    assert_eq!(from_lines()?, expected);
+
+    /* and when compacting */
+    Command::cargo_bin(env!("CARGO_PKG_NAME"))
+        .unwrap()
+        .arg("compact")
+        .arg(maildir.path())
+        .assert()
+        .success();
+    assert_eq!(from_lines()?, expected);
+    Ok(())
+}